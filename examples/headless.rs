@@ -0,0 +1,40 @@
+//! Runs a match between bots without a window, printing what happens.
+//!
+//! `cargo run --example headless -- --players 4 --difficulty hard`
+
+use bevy::{app::AppExit, prelude::*};
+use bevy_ggrs::SessionType;
+use bevy_hex::{components::Pcg32RandomT, settings::*, *};
+
+fn main() {
+    let options = LaunchOptions::load();
+    let settings = MatchSettings::from_options(&options);
+    let session = SessionSettings {
+        mode: SessionMode::SyncTest,
+        bots: settings.num_players,
+        ..SessionSettings::from_options(&options, &settings)
+    };
+
+    App::new()
+        .insert_resource(Pcg32RandomT::new(session.seed, 1))
+        .insert_resource(local_session(&settings, &session))
+        .insert_resource(SessionType::SyncTestSession)
+        .insert_resource(settings)
+        .insert_resource(session)
+        .add_plugins(MinimalPlugins)
+        .add_plugin(SnakeSimPlugin)
+        .add_system(print_match_events)
+        .run();
+}
+
+fn print_match_events(
+    mut match_events: EventReader<MatchEvent>,
+    mut exit: EventWriter<AppExit>,
+) {
+    for event in match_events.iter() {
+        println!("{:?}", event);
+        if let MatchEvent::MatchOver { .. } = event {
+            exit.send(AppExit);
+        }
+    }
+}
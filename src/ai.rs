@@ -0,0 +1,130 @@
+use std::collections::{hash_map::Entry, HashMap, HashSet, VecDeque};
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+
+use crate::{
+    components::*,
+    input::SnakeInput,
+    settings::Difficulty,
+    WorldSize,
+};
+
+/// What a bot gets to look at when choosing its next direction
+#[derive(SystemParam)]
+pub struct BotSenses<'w, 's> {
+    heads: Query<'w, 's, (&'static Player, &'static Hex, &'static Head, &'static Body), Without<Dead>>,
+    obstacles: Query<'w, 's, &'static Hex, Or<(With<Head>, With<Segment>)>>,
+    crumples: Query<'w, 's, &'static Hex, With<Crumple>>,
+    world_size: Res<'w, WorldSize>,
+}
+
+impl<'w, 's> BotSenses<'w, 's> {
+    /// Hexes a head standing on `hex` can step onto
+    fn free_neighbors(&self, hex: Hex, blocked: &HashSet<Hex>) -> Vec<(Direction, Hex)> {
+        Direction::ALL
+            .into_iter()
+            .map(|direction| (direction, self.world_size.wrap(hex.neighbor(direction))))
+            .filter(|(_, next)| self.world_size.contains(*next) && !blocked.contains(next))
+            .collect()
+    }
+
+    /// Number of free hexes reachable from `start`
+    fn reachable(&self, start: Hex, blocked: &HashSet<Hex>) -> usize {
+        let mut seen = HashSet::from([start]);
+        let mut queue = VecDeque::from([start]);
+        while let Some(hex) = queue.pop_front() {
+            for (_, next) in self.free_neighbors(hex, blocked) {
+                if seen.insert(next) {
+                    queue.push_back(next);
+                }
+            }
+        }
+        seen.len()
+    }
+
+    /// Breadth first search from `start` to the closest crumple, returns the
+    /// first step of the path
+    fn path_to_crumple(&self, start: Hex, first_steps: &[(Direction, Hex)], blocked: &HashSet<Hex>) -> Option<Direction> {
+        let crumples: HashSet<Hex> = self.crumples.iter().copied().collect();
+        let mut first_step: HashMap<Hex, Direction> = HashMap::new();
+        let mut queue = VecDeque::new();
+        for (direction, next) in first_steps {
+            first_step.insert(*next, *direction);
+            queue.push_back(*next);
+        }
+        first_step.entry(start).or_insert(Direction::None);
+        while let Some(hex) = queue.pop_front() {
+            let direction = first_step[&hex];
+            if crumples.contains(&hex) {
+                return Some(direction);
+            }
+            for (_, next) in self.free_neighbors(hex, blocked) {
+                if let Entry::Vacant(entry) = first_step.entry(next) {
+                    entry.insert(direction);
+                    queue.push_back(next);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Input of the bot playing `handle`, encoded like that of a human player.
+/// Bots are always ready for the next round.
+pub fn bot_input(handle: usize, difficulty: Difficulty, senses: &BotSenses) -> SnakeInput {
+    SnakeInput::steer(bot_direction(handle, difficulty, senses)).with_flags(SnakeInput::READY)
+}
+
+fn bot_direction(handle: usize, difficulty: Difficulty, senses: &BotSenses) -> Direction {
+    let (hex, head, body) = match senses.heads.iter().find(|(player, ..)| player.handle == handle) {
+        Some((_, hex, head, body)) => (*hex, head, body),
+        None => return Direction::None,
+    };
+
+    let mut blocked: HashSet<Hex> = senses.obstacles.iter().copied().filter(|obstacle| *obstacle != hex).collect();
+    if difficulty == Difficulty::Hard {
+        // wherever another head may step next
+        for (player, other, ..) in senses.heads.iter() {
+            if player.handle != handle {
+                blocked.extend(other.neighbors().map(|next| senses.world_size.wrap(next)));
+            }
+        }
+    }
+
+    let has_neck = body.0.len() > 1;
+    let candidates: Vec<(Direction, Hex)> = senses
+        .free_neighbors(hex, &blocked)
+        .into_iter()
+        .filter(|(direction, _)| !(has_neck && *direction == head.last_direction.opposite()))
+        .collect();
+    if candidates.is_empty() {
+        // nothing is safe, keep going and hope for the best
+        return Direction::None;
+    }
+
+    let direction = match difficulty {
+        Difficulty::Easy => candidates
+            .iter()
+            .min_by_key(|(_, next)| senses.crumples.iter().map(|crumple| next.distance(*crumple)).min().unwrap_or(0))
+            .map(|(direction, _)| *direction),
+        Difficulty::Normal => senses.path_to_crumple(hex, &candidates, &blocked),
+        Difficulty::Hard => {
+            // only consider steps that leave enough room to fit the whole snake
+            let roomy: Vec<(Direction, Hex)> = candidates
+                .iter()
+                .copied()
+                .filter(|(_, next)| senses.reachable(*next, &blocked) >= body.0.len())
+                .collect();
+            senses.path_to_crumple(hex, &roomy, &blocked)
+        }
+    };
+
+    // without a way to food stay alive as long as possible
+    direction.unwrap_or_else(|| {
+        candidates
+            .iter()
+            .max_by_key(|(_, next)| senses.reachable(*next, &blocked))
+            .map(|(direction, _)| *direction)
+            .unwrap_or(Direction::None)
+    })
+}
@@ -0,0 +1,284 @@
+use std::{fmt, str::FromStr};
+
+use bevy::prelude::*;
+
+use crate::{
+    components::Direction,
+    input::{chord, turn_flags, SnakeInput},
+    settings::MatchSettings,
+};
+
+/// How a keyboard player steers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyLayout {
+    /// up, right, down and left keys, diagonal hex directions take two of them
+    Chord([KeyCode; 4]),
+    /// one key per hex direction, in `Direction::ALL` order
+    Hex([KeyCode; 6]),
+    /// turn left and turn right keys, relative to where the head is going
+    Turn([KeyCode; 2]),
+}
+
+impl KeyLayout {
+    pub const WASD: KeyLayout = KeyLayout::Chord([KeyCode::W, KeyCode::D, KeyCode::S, KeyCode::A]);
+    pub const ARROWS: KeyLayout = KeyLayout::Chord([KeyCode::Up, KeyCode::Right, KeyCode::Down, KeyCode::Left]);
+    /// Q W E over A S D, going clockwise from W: W E D S A Q
+    pub const QWEASD: KeyLayout =
+        KeyLayout::Hex([KeyCode::W, KeyCode::E, KeyCode::D, KeyCode::S, KeyCode::A, KeyCode::Q]);
+    pub const NUMPAD: KeyLayout = KeyLayout::Hex([
+        KeyCode::Numpad9,
+        KeyCode::Numpad6,
+        KeyCode::Numpad3,
+        KeyCode::Numpad1,
+        KeyCode::Numpad4,
+        KeyCode::Numpad7,
+    ]);
+    pub const TURN_AD: KeyLayout = KeyLayout::Turn([KeyCode::A, KeyCode::D]);
+    pub const TURN_ARROWS: KeyLayout = KeyLayout::Turn([KeyCode::Left, KeyCode::Right]);
+
+    /// Built in layouts by name
+    pub const PROFILES: [(&'static str, KeyLayout); 6] = [
+        ("wasd", KeyLayout::WASD),
+        ("arrows", KeyLayout::ARROWS),
+        ("qweasd", KeyLayout::QWEASD),
+        ("numpad", KeyLayout::NUMPAD),
+        ("turn-ad", KeyLayout::TURN_AD),
+        ("turn-arrows", KeyLayout::TURN_ARROWS),
+    ];
+
+    pub fn keys(&self) -> &[KeyCode] {
+        match self {
+            KeyLayout::Chord(keys) => keys,
+            KeyLayout::Hex(keys) => keys,
+            KeyLayout::Turn(keys) => keys,
+        }
+    }
+
+    /// Direction the held keys point in, or the turns they make
+    pub fn input(&self, keys: &Input<KeyCode>) -> SnakeInput {
+        match self {
+            KeyLayout::Chord([up, right, down, left]) => SnakeInput::steer(chord(
+                keys.pressed(*up),
+                keys.pressed(*right),
+                keys.pressed(*down),
+                keys.pressed(*left),
+            )),
+            KeyLayout::Hex(layout) => SnakeInput::steer(
+                Direction::ALL
+                    .into_iter()
+                    .zip(layout)
+                    .find(|(_, key)| keys.pressed(**key))
+                    .map_or(Direction::None, |(direction, _)| direction),
+            ),
+            KeyLayout::Turn([left, right]) => {
+                SnakeInput::NONE.with_flags(turn_flags(keys.pressed(*left), keys.pressed(*right)))
+            }
+        }
+    }
+}
+
+impl fmt::Display for KeyLayout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((name, _)) = Self::PROFILES.iter().find(|(_, layout)| layout == self) {
+            return write!(f, "{}", name);
+        }
+        let kind = match self {
+            KeyLayout::Chord(_) => "chord",
+            KeyLayout::Hex(_) => "hex",
+            KeyLayout::Turn(_) => "turn",
+        };
+        write!(f, "{}", kind)?;
+        for key in self.keys() {
+            write!(f, " {:?}", key)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for KeyLayout {
+    type Err = String;
+
+    /// A profile name or `chord` followed by four, `hex` by six and `turn` by
+    /// two key names
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let kind = words.next().unwrap_or_default();
+        if let Some((_, layout)) = Self::PROFILES.iter().find(|(name, _)| *name == kind) {
+            return Ok(*layout);
+        }
+        let keys = words
+            .map(|word| key_from_name(word).ok_or_else(|| format!("unknown key {:?}", word)))
+            .collect::<Result<Vec<KeyCode>, _>>()?;
+        match kind {
+            "chord" => keys.try_into().map(KeyLayout::Chord).map_err(|_| "chord takes 4 keys".to_string()),
+            "hex" => keys.try_into().map(KeyLayout::Hex).map_err(|_| "hex takes 6 keys".to_string()),
+            "turn" => keys.try_into().map(KeyLayout::Turn).map_err(|_| "turn takes 2 keys".to_string()),
+            _ => Err(format!("unknown layout {:?}, expected a profile, chord, hex or turn", kind)),
+        }
+    }
+}
+
+/// Keys a layout may use, named like their `KeyCode` variant
+const BINDABLE_KEYS: [KeyCode; 60] = [
+    KeyCode::A, KeyCode::B, KeyCode::C, KeyCode::D, KeyCode::E, KeyCode::F, KeyCode::G,
+    KeyCode::H, KeyCode::I, KeyCode::J, KeyCode::K, KeyCode::L, KeyCode::M, KeyCode::N,
+    KeyCode::O, KeyCode::P, KeyCode::Q, KeyCode::R, KeyCode::S, KeyCode::T, KeyCode::U,
+    KeyCode::V, KeyCode::W, KeyCode::X, KeyCode::Y, KeyCode::Z,
+    KeyCode::Key0, KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4,
+    KeyCode::Key5, KeyCode::Key6, KeyCode::Key7, KeyCode::Key8, KeyCode::Key9,
+    KeyCode::Numpad0, KeyCode::Numpad1, KeyCode::Numpad2, KeyCode::Numpad3, KeyCode::Numpad4,
+    KeyCode::Numpad5, KeyCode::Numpad6, KeyCode::Numpad7, KeyCode::Numpad8, KeyCode::Numpad9,
+    KeyCode::Up, KeyCode::Right, KeyCode::Down, KeyCode::Left,
+    KeyCode::Space, KeyCode::Comma, KeyCode::Period, KeyCode::Slash, KeyCode::Semicolon,
+    KeyCode::Apostrophe, KeyCode::LBracket, KeyCode::RBracket, KeyCode::LShift, KeyCode::RShift,
+];
+
+pub fn key_from_name(name: &str) -> Option<KeyCode> {
+    BINDABLE_KEYS.into_iter().find(|key| format!("{:?}", key) == name)
+}
+
+pub fn is_bindable(key: KeyCode) -> bool {
+    BINDABLE_KEYS.contains(&key)
+}
+
+/// Boost, use item and ready keys of a keyboard player
+pub struct ActionKeys {
+    pub boost: KeyCode,
+    pub use_item: KeyCode,
+    pub ready: KeyCode,
+}
+
+impl ActionKeys {
+    pub fn flags(&self, keys: &Input<KeyCode>) -> u8 {
+        keys.pressed(self.boost) as u8 * SnakeInput::BOOST
+            | keys.pressed(self.use_item) as u8 * SnakeInput::USE_ITEM
+            | keys.pressed(self.ready) as u8 * SnakeInput::READY
+    }
+}
+
+/// Action keys of the first two keyboard players, one set on each side of the keyboard
+pub const ACTION_KEYS: [ActionKeys; 2] = [
+    ActionKeys { boost: KeyCode::LShift, use_item: KeyCode::LControl, ready: KeyCode::Space },
+    ActionKeys { boost: KeyCode::RShift, use_item: KeyCode::RControl, ready: KeyCode::Return },
+];
+
+/// Gamepads explicitly given to a player handle in local sessions. Handles
+/// without a keyboard layout or an assigned gamepad get the remaining
+/// connected gamepads in id order.
+#[derive(Clone, Debug, Default)]
+pub struct GamepadAssignments {
+    pub players: Vec<Option<Gamepad>>,
+}
+
+impl GamepadAssignments {
+    pub fn get(&self, handle: usize) -> Option<Gamepad> {
+        self.players.get(handle).copied().flatten()
+    }
+
+    pub fn is_assigned(&self, gamepad: Gamepad) -> bool {
+        self.players.contains(&Some(gamepad))
+    }
+
+    /// Give `gamepad` to `handle`, taking it away from any other player
+    pub fn assign(&mut self, handle: usize, gamepad: Gamepad) {
+        self.unassign(gamepad);
+        if self.players.len() <= handle {
+            self.players.resize(handle + 1, None);
+        }
+        self.players[handle] = Some(gamepad);
+    }
+
+    pub fn unassign(&mut self, gamepad: Gamepad) {
+        for player in self.players.iter_mut().filter(|player| **player == Some(gamepad)) {
+            *player = None;
+        }
+    }
+}
+
+/// Keyboard layout of every player sitting at this machine, by player
+/// handle. Online all of them steer the single local player.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyBindings {
+    pub players: Vec<KeyLayout>,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self { players: vec![KeyLayout::WASD, KeyLayout::ARROWS] }
+    }
+}
+
+impl KeyBindings {
+    /// One `player<N> <layout>` line per keyboard player
+    pub fn to_text(&self) -> String {
+        self.players
+            .iter()
+            .enumerate()
+            .map(|(i, layout)| format!("player{} {}\n", i + 1, layout))
+            .collect()
+    }
+
+    /// At least one and at most `MatchSettings::MAX_PLAYERS` players
+    pub fn from_text(text: &str) -> Result<Self, String> {
+        let mut players = Vec::new();
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let (key, layout) = line.split_once(' ').ok_or_else(|| format!("unexpected line {:?}", line))?;
+            let player: usize = key
+                .strip_prefix("player")
+                .and_then(|number| number.parse().ok())
+                .filter(|number| (1..=MatchSettings::MAX_PLAYERS).contains(number))
+                .ok_or_else(|| format!("expected player1 to player{}, got {:?}", MatchSettings::MAX_PLAYERS, key))?;
+            if players.len() < player {
+                players.resize(player, None);
+            }
+            players[player - 1] = Some(layout.parse()?);
+        }
+        let players = players
+            .into_iter()
+            .enumerate()
+            .map(|(i, layout)| layout.ok_or_else(|| format!("player{} has no layout", i + 1)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if players.is_empty() {
+            return Err("no players".to_string());
+        }
+        Ok(Self { players })
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load(path: &str) -> std::io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Self::from_text(&text).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        std::fs::write(path, self.to_text())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_round_trip() {
+        let bindings = KeyBindings {
+            players: vec![
+                KeyLayout::QWEASD,
+                KeyLayout::Chord([KeyCode::I, KeyCode::L, KeyCode::K, KeyCode::J]),
+                KeyLayout::Turn([KeyCode::N, KeyCode::M]),
+            ],
+        };
+        assert_eq!(KeyBindings::from_text(&bindings.to_text()), Ok(bindings));
+    }
+
+    #[test]
+    fn rejects_missing_and_out_of_range_players() {
+        assert!(KeyBindings::from_text("").is_err());
+        assert!(KeyBindings::from_text("# only a comment\n").is_err());
+        assert!(KeyBindings::from_text("player0 wasd").is_err());
+        assert!(KeyBindings::from_text("player999999999 wasd").is_err());
+        assert!(KeyBindings::from_text("player2 wasd").is_err());
+        assert!(KeyBindings::from_text("player1 hex W E D").is_err());
+    }
+}
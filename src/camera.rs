@@ -0,0 +1,74 @@
+use bevy::{input::mouse::MouseWheel, prelude::*};
+use bevy_ggrs::SessionType;
+use bevy_hex::components::{Head, Player};
+
+/// Pixels per second the spectator camera pans at scale 1
+const PAN_SPEED: f32 = 600.;
+
+const MIN_SCALE: f32 = 0.5;
+const MAX_SCALE: f32 = 6.;
+
+/// Free camera for spectators, who have no snake to look after: WASD or the
+/// arrow keys pan, Q, E or the mouse wheel zoom, 1 to 8 follow the head of
+/// that player and 0 lets go of it again.
+#[allow(clippy::type_complexity)]
+pub fn spectator_camera(
+    session: Option<Res<SessionType>>,
+    keys: Res<Input<KeyCode>>,
+    time: Res<Time>,
+    mut wheel: EventReader<MouseWheel>,
+    mut following: Local<Option<usize>>,
+    heads: Query<(&Player, &Transform), (With<Head>, Without<Camera>)>,
+    mut cameras: Query<&mut Transform, With<Camera>>,
+) {
+    if !matches!(session.as_deref(), Some(SessionType::SpectatorSession)) {
+        *following = None;
+        return;
+    }
+    let mut camera = match cameras.get_single_mut() {
+        Ok(camera) => camera,
+        Err(_) => return,
+    };
+
+    const FOLLOW_KEYS: [KeyCode; 9] = [
+        KeyCode::Key0, KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4,
+        KeyCode::Key5, KeyCode::Key6, KeyCode::Key7, KeyCode::Key8,
+    ];
+    if let Some(key) = FOLLOW_KEYS.iter().position(|key| keys.just_pressed(*key)) {
+        *following = key.checked_sub(1);
+    }
+
+    let held = |a: KeyCode, b: KeyCode| (keys.pressed(a) || keys.pressed(b)) as i32 as f32;
+    let pan = Vec2::new(
+        held(KeyCode::D, KeyCode::Right) - held(KeyCode::A, KeyCode::Left),
+        held(KeyCode::W, KeyCode::Up) - held(KeyCode::S, KeyCode::Down),
+    );
+    if pan != Vec2::ZERO {
+        *following = None;
+    }
+
+    let mut zoom = (keys.pressed(KeyCode::Q) as i32 - keys.pressed(KeyCode::E) as i32) as f32 * time.delta_seconds() * 2.;
+    for event in wheel.iter() {
+        zoom -= event.y * 0.1;
+    }
+    let scale = (camera.scale.x * (1. + zoom)).clamp(MIN_SCALE, MAX_SCALE);
+    camera.scale = Vec3::new(scale, scale, 1.);
+
+    let target = following.and_then(|handle| {
+        heads
+            .iter()
+            .find(|(player, _)| player.handle == handle)
+            .map(|(_, transform)| transform.translation.truncate())
+    });
+    match target {
+        Some(target) => {
+            camera.translation.x = target.x;
+            camera.translation.y = target.y;
+        }
+        None => {
+            let step = pan * PAN_SPEED * scale * time.delta_seconds();
+            camera.translation.x += step.x;
+            camera.translation.y += step.y;
+        }
+    }
+}
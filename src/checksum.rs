@@ -0,0 +1,149 @@
+use std::{
+    collections::BTreeMap,
+    hash::{Hash, Hasher},
+};
+
+use bevy::prelude::*;
+use bevy_ggrs::{Rollback, SessionType};
+
+use crate::{components::*, hash::StableHasher, MatchPhase, MatchScore, RoundResult};
+
+/// Frames of checksums kept around to compare against reports of peers
+pub const CHECKSUM_HISTORY: usize = 10 * crate::FPS;
+
+/// Rollback frames simulated since the match started, part of the rollback state
+#[derive(Reflect, Default, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[reflect(Resource, Hash)]
+pub struct FrameCount(pub i32);
+
+/// Hash of the rollback state, split into one lane per group of components
+/// so a mismatch tells which part of the simulation diverged
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checksum(pub [u64; 8]);
+
+impl Checksum {
+    pub const LANES: [&'static str; 8] = [
+        "Hex/HexHistory",
+        "Head",
+        "Body",
+        "Tail/Crumple/Segment/Dead",
+        "Score/Length",
+        "MovementCooldown",
+        "Pcg32RandomT",
+        "MatchPhase/MatchScore/RoundResult",
+    ];
+
+    /// Names of the lanes that differ between the two checksums
+    pub fn differing(&self, other: &Checksum) -> Vec<&'static str> {
+        Self::LANES
+            .iter()
+            .zip(self.0.iter().zip(other.0.iter()))
+            .filter(|(_, (a, b))| a != b)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        self.0.iter().flat_map(|lane| lane.to_le_bytes()).collect()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut lanes = [0; 8];
+        if bytes.len() != lanes.len() * 8 {
+            return None;
+        }
+        for (lane, chunk) in lanes.iter_mut().zip(bytes.chunks_exact(8)) {
+            *lane = u64::from_le_bytes(chunk.try_into().ok()?);
+        }
+        Some(Self(lanes))
+    }
+}
+
+/// Checksum of every recently simulated frame. Resimulating a frame after a
+/// rollback overwrites its entry, so once a frame is confirmed its entry is final.
+#[derive(Default)]
+pub struct ChecksumHistory(pub BTreeMap<i32, Checksum>);
+
+/// First desync found in the running match
+#[derive(Clone, Debug)]
+pub struct DesyncReport {
+    pub frame: i32,
+    /// the peer that reported a different checksum, `None` for sync tests
+    pub peer: Option<String>,
+    pub components: Vec<&'static str>,
+}
+
+fn hash_of(value: impl Hash) -> u64 {
+    let mut hasher = StableHasher::default();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Hash `(rollback id, value)` pairs independently of the query order, entity
+/// ids differ between peers but rollback ids don't
+fn lane<T: Hash>(mut values: Vec<(u32, T)>) -> u64 {
+    values.sort_by_key(|(id, _)| *id);
+    hash_of(values)
+}
+
+/// Checksum the rollback state after every simulated frame. In sync test
+/// sessions a resimulated frame has to match its first simulation exactly.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+pub fn record_checksum(
+    mut commands: Commands,
+    mut frame: ResMut<FrameCount>,
+    mut history: ResMut<ChecksumHistory>,
+    report: Option<Res<DesyncReport>>,
+    session: Option<Res<SessionType>>,
+    rollback_query: Query<&Rollback>,
+    hex_query: Query<(&Rollback, &Hex, Option<&HexHistory>)>,
+    head_query: Query<(&Rollback, &Head)>,
+    body_query: Query<(&Rollback, &Body)>,
+    marker_query: Query<(&Rollback, Option<&Tail>, Option<&Crumple>, Option<&Segment>, Option<&Dead>)>,
+    score_query: Query<(&Rollback, &Score, &Length)>,
+    cooldown_query: Query<(&Rollback, &MovementCooldown)>,
+    rng: Option<Res<Pcg32RandomT>>,
+    match_state: (Res<MatchPhase>, Res<MatchScore>, Res<RoundResult>),
+) {
+    frame.0 += 1;
+
+    let (phase, match_score, round_result) = match_state;
+    let checksum = Checksum([
+        lane(hex_query.iter().map(|(rollback, hex, history)| (rollback.id(), (*hex, history))).collect()),
+        lane(head_query.iter().map(|(rollback, head)| (rollback.id(), head)).collect()),
+        lane(
+            body_query
+                .iter()
+                .map(|(rollback, body)| {
+                    let ids: Vec<Option<u32>> = body.0.iter().map(|entity| rollback_query.get(*entity).ok().map(Rollback::id)).collect();
+                    (rollback.id(), ids)
+                })
+                .collect(),
+        ),
+        lane(
+            marker_query
+                .iter()
+                .map(|(rollback, tail, crumple, segment, dead)| {
+                    (rollback.id(), (tail.is_some(), crumple.is_some(), segment.is_some(), dead.is_some()))
+                })
+                .collect(),
+        ),
+        lane(score_query.iter().map(|(rollback, score, length)| (rollback.id(), (score, length))).collect()),
+        lane(cooldown_query.iter().map(|(rollback, cooldown)| (rollback.id(), cooldown)).collect()),
+        hash_of(rng.as_deref()),
+        hash_of((&*phase, &*match_score, &*round_result)),
+    ]);
+
+    if let Some(previous) = history.0.insert(frame.0, checksum) {
+        let sync_test = matches!(session.as_deref(), Some(SessionType::SyncTestSession));
+        if sync_test && previous != checksum && report.is_none() {
+            let components = previous.differing(&checksum);
+            error!("resimulating frame {} changed {:?}", frame.0, components);
+            commands.insert_resource(DesyncReport { frame: frame.0, peer: None, components });
+        }
+    }
+    while history.0.len() > CHECKSUM_HISTORY {
+        let oldest = *history.0.keys().next().unwrap();
+        history.0.remove(&oldest);
+    }
+}
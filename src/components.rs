@@ -1,66 +1,154 @@
-use std::convert::TryInto;
+use std::hash::Hasher;
 
 use bevy::prelude::*;
 
-#[derive(Clone,Component,Reflect, Default, Copy, Debug, PartialEq)]
-pub enum Direction {
-    UpRight,
-    Right,
-    DownRight,
-    DownLeft,
-    Left,
-    UpLeft,
-    #[default]
-    None
-}
+pub use crate::hex::{Direction, Hex};
+use crate::hash::StableHasher;
 
-#[derive(Component)]
+#[derive(Component, Reflect, Default, Hash)]
+#[reflect(Component, Hash)]
 pub struct Player {
     pub handle: usize,
 }
 
-#[derive(Component)]
-pub struct Following(pub Entity);
-
-#[derive(Component)]
-pub struct Follower(pub Entity);
-
-#[derive(Component, Reflect, Default, Clone, Copy)]
-pub struct Hex {
-    pub q: f32,
-    pub r: f32,
-    pub z: f32,
-}
-
-#[derive(Component)]
+/// Hexes a head or segment stepped off, oldest first
+#[derive(Component, Reflect, Default, Hash)]
+#[reflect(Component, Hash)]
 pub struct HexHistory(pub Vec<Hex>);
 
-#[derive(Component, Reflect, Default)]
+#[derive(Component, Reflect, Default, Hash)]
+#[reflect(Component, Hash)]
 pub struct Head {
+    /// direction of the next step
     pub direction: Direction,
-    pub last_direction: Direction
+    /// direction of the previous step
+    pub last_direction: Direction,
+    /// a second turn made before the next step, applied after it
+    pub queued_direction: Direction,
+    /// turn input flags of the previous frame, a held turn key turns once
+    pub held_turns: u8,
+    /// steps twice as often while boost is held
+    pub boosting: bool,
+}
+
+impl Head {
+    pub fn new(direction: Direction) -> Self {
+        Self { direction, last_direction: direction, queued_direction: Direction::None, held_turns: 0, boosting: false }
+    }
+
+    /// Turn `steps` sixths of a revolution, positive is clockwise, from the
+    /// latest heading: the queued turn, else the next step.
+    pub fn turn(&mut self, steps: i32, has_neck: bool) {
+        let heading = match self.queued_direction {
+            Direction::None => self.direction,
+            queued => queued,
+        };
+        self.steer(heading.rotate(steps), has_neck);
+    }
+
+    /// Steer towards `direction`. With a neck, turning straight back onto it
+    /// is refused. Once the head already turned since its last step, a
+    /// further turn is queued for the step after.
+    pub fn steer(&mut self, direction: Direction, has_neck: bool) {
+        if direction == Direction::None || direction == self.direction {
+            return;
+        }
+        if self.direction == self.last_direction {
+            if !(has_neck && direction == self.last_direction.opposite()) {
+                self.direction = direction;
+            }
+        } else if !(has_neck && direction == self.direction.opposite()) {
+            self.queued_direction = direction;
+        }
+    }
+
+    /// Called after every step
+    pub fn stepped(&mut self) {
+        self.last_direction = self.direction;
+        if self.queued_direction != Direction::None {
+            self.direction = self.queued_direction;
+            self.queued_direction = Direction::None;
+        }
+    }
 }
 
 #[derive(Component, Reflect, Default)]
+#[reflect(Component)]
 pub struct Body(pub Vec<Entity>);
 
-#[derive(Component, Reflect, Default)]
+#[derive(Component, Reflect, Default, Hash)]
+#[reflect(Component, Hash)]
 pub struct Tail;
 
-#[derive(Component, Reflect, Default)]
+#[derive(Component, Reflect, Default, Hash)]
+#[reflect(Component, Hash)]
 pub struct Crumple;
 
-#[derive(Component, Reflect, Default)]
+#[derive(Component, Reflect, Default, Hash)]
+#[reflect(Component, Hash)]
 pub struct Segment;
 
-#[derive(Component, Reflect, Default)]
+/// Crumples eaten by the player owning this head
+#[derive(Component, Reflect, Default, Clone, Copy, Hash)]
+#[reflect(Component, Hash)]
+pub struct Score(pub u32);
+
+/// Number of hexes the snake covers, counting growth that has been eaten but
+/// not spawned yet
+#[derive(Component, Reflect, Default, Clone, Copy, Hash)]
+#[reflect(Component, Hash)]
+pub struct Length(pub u32);
+
+/// Marks a head that has crashed, dead snakes stay on the board as obstacles
+#[derive(Component, Reflect, Default, Hash)]
+#[reflect(Component, Hash)]
+pub struct Dead;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeathCause {
+    /// ran into a body segment of the snake belonging to `handle`, possibly its own
+    Body { handle: usize },
+    /// met the head of the snake belonging to `handle` and was not the longer one
+    HeadOn { handle: usize },
+    /// left the map while the world has solid walls
+    Wall,
+    /// the peer of the player disconnected
+    Forfeit,
+}
+
+/// Counts rollback frames between steps instead of wall-clock time so that
+/// resimulated frames advance exactly like the original ones
+#[derive(Component, Reflect, Default, Hash)]
+#[reflect(Component, Hash)]
 pub struct MovementCooldown {
-    pub timer: Timer,
+    /// number of frames between two steps
+    pub interval: u32,
+    pub elapsed: u32,
 }
 
+impl MovementCooldown {
+    pub fn new(interval: u32) -> Self {
+        Self { interval, elapsed: 0 }
+    }
 
+    /// Advance by one frame, or two while boosting, returns true on the
+    /// frames where the head moves
+    pub fn tick(&mut self, boosted: bool) -> bool {
+        self.elapsed += if boosted { 2 } else { 1 };
+        if self.elapsed >= self.interval {
+            self.elapsed = 0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+
+/// Shared crumple rng, a resource registered for rollback so resimulated
+/// frames draw the same numbers again
 #[derive(Default, Reflect, Hash, Component, Clone, Copy)]
-#[reflect(Hash)]
+#[reflect(Resource, Hash)]
 pub struct Pcg32RandomT {
     state: u64,
     inc: u64
@@ -72,10 +160,35 @@ impl Pcg32RandomT {
         Self{state: initstate, inc: (initseq << 1u64) | 1u64,}
     }
 
+    /// Internal `(state, inc)`, restores with `from_raw`
+    pub fn raw(self) -> (u64, u64) {
+        (self.state, self.inc)
+    }
+
+    pub fn from_raw(state: u64, inc: u64) -> Self {
+        Self { state, inc }
+    }
+
+    /// Rng every peer of a match agrees on without talking to each other:
+    /// the seed hashes the sorted, deduplicated peer ids, so it does not
+    /// depend on the order in which peers connected.
+    pub fn from_peer_ids<'a>(ids: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut ids: Vec<&str> = ids.into_iter().collect();
+        ids.sort_unstable();
+        ids.dedup();
+        let mut hasher = StableHasher::default();
+        // hashed byte by byte, so the seed does not depend on how `Hash for str` is written
+        for id in ids {
+            hasher.write(id.as_bytes());
+            hasher.write_u8(0xff);
+        }
+        Self::new(hasher.finish(), 1)
+    }
+
     pub fn pcg32_random_r(&mut self) -> u32 {
         let oldstate = self.state;
         // Advance internal state
-        self.state = oldstate * 6364136223846793005u64 + self.inc;
+        self.state = oldstate.wrapping_mul(6364136223846793005u64).wrapping_add(self.inc);
         // Calculate output function (XSH RR), uses old state for max ILP
         let xorshifted = ((oldstate >> 18) ^ oldstate >> 27) as u32;
         let rot = oldstate >> 59;
@@ -104,4 +217,69 @@ impl Pcg32RandomT {
     }
 
 
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reversing_is_refused_only_with_a_neck() {
+        let mut head = Head::new(Direction::Right);
+        head.steer(Direction::Left, true);
+        assert_eq!(head.direction, Direction::Right);
+        assert_eq!(head.queued_direction, Direction::None);
+
+        head.steer(Direction::Left, false);
+        assert_eq!(head.direction, Direction::Left);
+    }
+
+    #[test]
+    fn second_turn_is_queued_until_the_step() {
+        let mut head = Head::new(Direction::Right);
+        head.steer(Direction::DownRight, true);
+        head.steer(Direction::DownLeft, true);
+        assert_eq!(head.direction, Direction::DownRight);
+        assert_eq!(head.queued_direction, Direction::DownLeft);
+
+        head.stepped();
+        assert_eq!(head.last_direction, Direction::DownRight);
+        assert_eq!(head.direction, Direction::DownLeft);
+        assert_eq!(head.queued_direction, Direction::None);
+
+        head.stepped();
+        assert_eq!(head.last_direction, Direction::DownLeft);
+        assert_eq!(head.direction, Direction::DownLeft);
+    }
+
+    #[test]
+    fn queued_reversal_of_the_new_heading_is_refused() {
+        let mut head = Head::new(Direction::Right);
+        head.steer(Direction::DownRight, true);
+        head.steer(Direction::UpLeft, true);
+        assert_eq!(head.direction, Direction::DownRight);
+        assert_eq!(head.queued_direction, Direction::None);
+
+        head.steer(Direction::UpLeft, false);
+        assert_eq!(head.queued_direction, Direction::UpLeft);
+    }
+
+    #[test]
+    fn turns_rotate_from_the_queued_heading() {
+        let mut head = Head::new(Direction::Right);
+        head.turn(-1, true);
+        assert_eq!(head.direction, Direction::UpRight);
+
+        let mut head = Head::new(Direction::Right);
+        head.turn(1, true);
+        head.turn(1, true);
+        assert_eq!(head.direction, Direction::DownRight);
+        assert_eq!(head.queued_direction, Direction::DownLeft);
+
+        head.turn(1, true);
+        assert_eq!(head.queued_direction, Direction::Left);
+        head.turn(-1, true);
+        head.turn(-1, true);
+        // turning back onto the next step leaves the queued turn alone
+        assert_eq!(head.queued_direction, Direction::DownLeft);
+    }
+}
@@ -0,0 +1,39 @@
+use std::hash::Hasher;
+
+/// 64 bit FNV-1a. Unlike `DefaultHasher` its output is specified, so every
+/// peer gets the same hash no matter which platform or compiler built it.
+/// `usize` and `isize`, e.g. `Vec` lengths and enum discriminants, are
+/// hashed as 8 bytes so 32 bit wasm peers agree with 64 bit native ones.
+pub struct StableHasher(u64);
+
+impl StableHasher {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+}
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self(Self::OFFSET_BASIS)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(Self::PRIME);
+        }
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write(&(i as u64).to_le_bytes());
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write(&(i as i64).to_le_bytes());
+    }
+}
@@ -0,0 +1,363 @@
+use std::ops::{Add, AddAssign, Mul, Neg, Sub, SubAssign};
+
+use bevy::prelude::*;
+
+/// The six directions a head can travel in, ordered clockwise as seen on screen
+/// (r grows downwards).
+#[derive(Clone, Component, Reflect, Default, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    UpRight,
+    Right,
+    DownRight,
+    DownLeft,
+    Left,
+    UpLeft,
+    #[default]
+    None
+}
+
+impl Direction {
+    /// All six real directions in clockwise order, starting at `UpRight`.
+    pub const ALL: [Direction; 6] = [
+        Direction::UpRight,
+        Direction::Right,
+        Direction::DownRight,
+        Direction::DownLeft,
+        Direction::Left,
+        Direction::UpLeft,
+    ];
+
+    /// Position of the direction in `Direction::ALL`, `None` has no index.
+    pub fn index(self) -> Option<usize> {
+        Direction::ALL.iter().position(|d| *d == self)
+    }
+
+    /// Unit step in axial coordinates.
+    pub fn offset(self) -> Hex {
+        match self {
+            Direction::UpRight => Hex::new(1, -1),
+            Direction::Right => Hex::new(1, 0),
+            Direction::DownRight => Hex::new(0, 1),
+            Direction::DownLeft => Hex::new(-1, 1),
+            Direction::Left => Hex::new(-1, 0),
+            Direction::UpLeft => Hex::new(0, -1),
+            Direction::None => Hex::ZERO,
+        }
+    }
+
+    /// Inverse of `offset` for unit steps.
+    pub fn from_offset(offset: Hex) -> Direction {
+        Direction::ALL
+            .into_iter()
+            .find(|d| d.offset() == offset)
+            .unwrap_or(Direction::None)
+    }
+
+    pub fn opposite(self) -> Direction {
+        self.rotate(3)
+    }
+
+    /// Turn 60° clockwise.
+    pub fn rotate_right(self) -> Direction {
+        self.rotate(1)
+    }
+
+    /// Turn 60° counter-clockwise.
+    pub fn rotate_left(self) -> Direction {
+        self.rotate(-1)
+    }
+
+    /// Nearest direction to an angle in radians, counter-clockwise from the
+    /// x axis with y pointing up the screen like `Hex::to_pixel`.
+    pub fn from_angle(angle: f32) -> Direction {
+        use std::f32::consts::FRAC_PI_3;
+        // `UpRight` points at 60°, every further direction 60° clockwise
+        let steps = ((FRAC_PI_3 - angle) / FRAC_PI_3).round() as i32;
+        Direction::ALL[steps.rem_euclid(6) as usize]
+    }
+
+    /// Turn by `steps` sixths of a revolution, positive is clockwise.
+    pub fn rotate(self, steps: i32) -> Direction {
+        match self.index() {
+            Some(i) => Direction::ALL[(i as i32 + steps).rem_euclid(6) as usize],
+            None => Direction::None,
+        }
+    }
+}
+
+/// Logical position on the board in axial coordinates. The third cube
+/// coordinate is implied: `s = -q - r`.
+#[derive(Component, Reflect, FromReflect, Default, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[reflect(Component, Hash)]
+pub struct Hex {
+    pub q: i32,
+    pub r: i32,
+}
+
+impl Hex {
+    pub const ZERO: Hex = Hex { q: 0, r: 0 };
+
+    pub const fn new(q: i32, r: i32) -> Self {
+        Self { q, r }
+    }
+
+    pub const fn s(self) -> i32 {
+        -self.q - self.r
+    }
+
+    /// Builds a hex from cube coordinates, `None` unless `q + r + s == 0`.
+    pub fn from_cube(q: i32, r: i32, s: i32) -> Option<Self> {
+        (q + r + s == 0).then(|| Self::new(q, r))
+    }
+
+    pub fn neighbor(self, direction: Direction) -> Hex {
+        self + direction.offset()
+    }
+
+    pub fn neighbors(self) -> [Hex; 6] {
+        Direction::ALL.map(|d| self.neighbor(d))
+    }
+
+    /// Distance from the origin.
+    pub fn length(self) -> i32 {
+        (self.q.abs() + self.r.abs() + self.s().abs()) / 2
+    }
+
+    pub fn distance(self, other: Hex) -> i32 {
+        (self - other).length()
+    }
+
+    /// All hexes exactly `radius` steps away, walking clockwise from the
+    /// `Left` corner. Going from index `i` to `i + 1` moves in
+    /// `Direction::ALL[i / radius]`.
+    pub fn ring(self, radius: u32) -> Vec<Hex> {
+        if radius == 0 {
+            return vec![self];
+        }
+        let mut results = Vec::with_capacity(6 * radius as usize);
+        let mut hex = self + Direction::Left.offset() * radius as i32;
+        for direction in Direction::ALL {
+            for _ in 0..radius {
+                results.push(hex);
+                hex = hex.neighbor(direction);
+            }
+        }
+        results
+    }
+
+    /// The hex itself followed by every ring up to and including `radius`.
+    pub fn spiral(self, radius: u32) -> Vec<Hex> {
+        (0..=radius).flat_map(|k| self.ring(k)).collect()
+    }
+
+    /// Hexes on the straight line from `self` to `other`, both ends included.
+    pub fn line_to(self, other: Hex) -> Vec<Hex> {
+        let n = self.distance(other);
+        if n == 0 {
+            return vec![self];
+        }
+        // nudge off the exact edge so ties always round the same way
+        let (aq, ar) = (self.q as f32 + 1e-6, self.r as f32 + 1e-6);
+        let (bq, br) = (other.q as f32 + 1e-6, other.r as f32 + 1e-6);
+        (0..=n)
+            .map(|i| {
+                let t = i as f32 / n as f32;
+                Hex::round(aq + (bq - aq) * t, ar + (br - ar) * t)
+            })
+            .collect()
+    }
+
+    /// Rounds fractional axial coordinates to the nearest hex.
+    pub fn round(q: f32, r: f32) -> Hex {
+        let s = -q - r;
+        let (mut rq, mut rr, rs) = (q.round(), r.round(), s.round());
+        let (dq, dr, ds) = ((rq - q).abs(), (rr - r).abs(), (rs - s).abs());
+        if dq > dr && dq > ds {
+            rq = -rr - rs;
+        } else if dr > ds {
+            rr = -rq - rs;
+        }
+        Hex::new(rq as i32, rr as i32)
+    }
+
+    /// Rotate 60° clockwise around the origin.
+    pub fn rotate_right(self) -> Hex {
+        Hex::new(-self.r, -self.s())
+    }
+
+    /// Rotate 60° counter-clockwise around the origin.
+    pub fn rotate_left(self) -> Hex {
+        Hex::new(-self.s(), -self.q)
+    }
+
+    /// Rotate by `steps` sixths of a revolution around `center`, positive is clockwise.
+    pub fn rotate_around(self, center: Hex, steps: i32) -> Hex {
+        let mut offset = self - center;
+        for _ in 0..steps.rem_euclid(6) {
+            offset = offset.rotate_right();
+        }
+        center + offset
+    }
+
+    /// Convert hex to pixel
+    /// size = distance from center to corner
+    /// x = size * (sqrt(3) * hex.q + sqrt(3)/2 * hex.r)
+    /// y = size * ( 3./2 * hex.r)
+    pub fn to_pixel(self, size: f32) -> Vec2 {
+        let (q, r) = (self.q as f32, self.r as f32);
+        Vec2::new(
+            size * (3.0_f32.sqrt() * q + 3.0_f32.sqrt() / 2. * r),
+            -size * (3. / 2. * r),
+        )
+    }
+}
+
+impl Add for Hex {
+    type Output = Hex;
+
+    fn add(self, rhs: Hex) -> Hex {
+        Hex::new(self.q + rhs.q, self.r + rhs.r)
+    }
+}
+
+impl AddAssign for Hex {
+    fn add_assign(&mut self, rhs: Hex) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub for Hex {
+    type Output = Hex;
+
+    fn sub(self, rhs: Hex) -> Hex {
+        Hex::new(self.q - rhs.q, self.r - rhs.r)
+    }
+}
+
+impl SubAssign for Hex {
+    fn sub_assign(&mut self, rhs: Hex) {
+        *self = *self - rhs;
+    }
+}
+
+impl Mul<i32> for Hex {
+    type Output = Hex;
+
+    fn mul(self, rhs: i32) -> Hex {
+        Hex::new(self.q * rhs, self.r * rhs)
+    }
+}
+
+impl Neg for Hex {
+    type Output = Hex;
+
+    fn neg(self) -> Hex {
+        Hex::new(-self.q, -self.r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_walks_clockwise_from_the_left_corner() {
+        assert_eq!(
+            Hex::ZERO.ring(1),
+            vec![Hex::new(-1, 0), Hex::new(0, -1), Hex::new(1, -1), Hex::new(1, 0), Hex::new(0, 1), Hex::new(-1, 1)]
+        );
+        let center = Hex::new(2, -3);
+        for radius in 1..5u32 {
+            let ring = center.ring(radius);
+            assert_eq!(ring.len(), 6 * radius as usize);
+            for (i, hex) in ring.iter().enumerate() {
+                assert_eq!(hex.distance(center), radius as i32);
+                let next = ring[(i + 1) % ring.len()];
+                assert_eq!(next, hex.neighbor(Direction::ALL[i / radius as usize]));
+            }
+        }
+        assert_eq!(center.ring(0), vec![center]);
+    }
+
+    #[test]
+    fn spiral_holds_every_hex_once() {
+        let spiral = Hex::ZERO.spiral(3);
+        assert_eq!(spiral.len(), 1 + 6 + 12 + 18);
+        let unique: std::collections::HashSet<Hex> = spiral.iter().copied().collect();
+        assert_eq!(unique.len(), spiral.len());
+        assert!(spiral.iter().all(|hex| hex.length() <= 3));
+    }
+
+    #[test]
+    fn distance_is_symmetric() {
+        let hexes = Hex::new(-2, 1).spiral(3);
+        for a in &hexes {
+            for b in &hexes {
+                assert_eq!(a.distance(*b), b.distance(*a));
+            }
+            assert_eq!(a.distance(*a), 0);
+        }
+        assert_eq!(Hex::new(3, -1).distance(Hex::new(-1, 2)), 4);
+    }
+
+    #[test]
+    fn line_steps_between_neighbours() {
+        let (from, to) = (Hex::new(-3, 1), Hex::new(2, -2));
+        let line = from.line_to(to);
+        assert_eq!(line.len(), from.distance(to) as usize + 1);
+        assert_eq!(line.first(), Some(&from));
+        assert_eq!(line.last(), Some(&to));
+        assert!(line.windows(2).all(|pair| pair[0].distance(pair[1]) == 1));
+        assert_eq!(from.line_to(from), vec![from]);
+    }
+
+    #[test]
+    fn round_breaks_ties_consistently() {
+        assert_eq!(Hex::round(0.2, -0.1), Hex::ZERO);
+        assert_eq!(Hex::round(0.5, 0.), Hex::new(1, 0));
+        assert_eq!(Hex::round(-0.5, 0.), Hex::new(-1, 0));
+        assert_eq!(Hex::round(0.5, -0.5), Hex::new(1, -1));
+        for hex in Hex::ZERO.spiral(2) {
+            assert_eq!(Hex::round(hex.q as f32, hex.r as f32), hex);
+        }
+    }
+
+    #[test]
+    fn full_rotation_returns_to_the_start() {
+        let center = Hex::new(1, 2);
+        for hex in center.spiral(2) {
+            assert_eq!(hex.rotate_around(center, 6), hex);
+            assert_eq!(hex.rotate_around(center, -6), hex);
+            assert_eq!(hex.rotate_around(center, 1), hex.rotate_around(center, -5));
+        }
+        let hex = Hex::new(2, -1);
+        assert_eq!(hex.rotate_right().rotate_left(), hex);
+        for direction in Direction::ALL {
+            assert_eq!(direction.rotate(6), direction);
+            assert_eq!(direction.opposite().opposite(), direction);
+            assert_eq!(direction.offset().rotate_right(), direction.rotate_right().offset());
+            assert_eq!(Direction::from_offset(direction.offset()), direction);
+        }
+        assert_eq!(Direction::None.rotate(1), Direction::None);
+    }
+
+    #[test]
+    fn angles_snap_to_the_nearest_direction() {
+        use std::f32::consts::{FRAC_PI_6, PI};
+        assert_eq!(Direction::from_angle(0.), Direction::Right);
+        assert_eq!(Direction::from_angle(PI / 3.), Direction::UpRight);
+        assert_eq!(Direction::from_angle(PI), Direction::Left);
+        assert_eq!(Direction::from_angle(-PI), Direction::Left);
+        assert_eq!(Direction::from_angle(-PI / 3. + 0.4), Direction::DownRight);
+        for direction in Direction::ALL {
+            let pixel = direction.offset().to_pixel(1.);
+            let angle = pixel.y.atan2(pixel.x);
+            assert_eq!(Direction::from_angle(angle), direction);
+            // anything within half a sector snaps to it, however many turns away
+            assert_eq!(Direction::from_angle(angle + FRAC_PI_6 * 0.9), direction);
+            assert_eq!(Direction::from_angle(angle - FRAC_PI_6 * 0.9), direction);
+            assert_eq!(Direction::from_angle(angle + 2. * PI), direction);
+            assert_eq!(Direction::from_angle(angle - 4. * PI), direction);
+        }
+    }
+}
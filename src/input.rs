@@ -1,66 +1,225 @@
-use bevy::prelude::*;
-
-pub const INPUT_UP_RIGHT: u8 = 1 << 0;
-pub const INPUT_RIGHT: u8 = 1 << 1;
-pub const INPUT_DOWN_RIGHT: u8 = 1 << 2;
-pub const INPUT_DOWN_LEFT: u8 = 1 << 3;
-pub const INPUT_LEFT: u8 = 1 << 4;
-pub const INPUT_UP_LEFT: u8 = 1 << 5;
-
-pub fn input(_: In<ggrs::PlayerHandle>, keys: Res<Input<KeyCode>>) -> u8 {
-    let mut input = 0u8;
-    if 
-        keys.any_pressed([KeyCode::Up, KeyCode::W]) && 
-        !keys.any_pressed([KeyCode::Right, KeyCode::D]) && 
-        !keys.any_pressed([KeyCode::Down, KeyCode::S]) && 
-        !keys.any_pressed([KeyCode::Left, KeyCode::A]) {
-            // head.direction = Direction::Up;
-    } else if 
-        keys.any_pressed([KeyCode::Up, KeyCode::W]) && 
-        keys.any_pressed([KeyCode::Right, KeyCode::D]) && 
-        !keys.any_pressed([KeyCode::Down, KeyCode::S]) &&
-        !keys.any_pressed([KeyCode::Left, KeyCode::A]) {
-            input |= INPUT_UP_RIGHT;
-    } else if 
-        !keys.any_pressed([KeyCode::Up, KeyCode::W]) &&
-        keys.any_pressed([KeyCode::Right, KeyCode::D]) && 
-        !keys.any_pressed([KeyCode::Down, KeyCode::S]) && 
-        !keys.any_pressed([KeyCode::Left, KeyCode::A]) {
-            input |= INPUT_RIGHT;
-    } else if 
-        !keys.any_pressed([KeyCode::Up, KeyCode::W]) &&
-        keys.any_pressed([KeyCode::Right, KeyCode::D]) && 
-        keys.any_pressed([KeyCode::Down, KeyCode::S]) && 
-        !keys.any_pressed([KeyCode::Left, KeyCode::A]) {
-            input |= INPUT_DOWN_RIGHT;
-    } else if
-        !keys.any_pressed([KeyCode::Up, KeyCode::W]) &&
-        !keys.any_pressed([KeyCode::Right, KeyCode::D]) && 
-        keys.any_pressed([KeyCode::Down, KeyCode::S]) && 
-        !keys.any_pressed([KeyCode::Left, KeyCode::A]) {
-            // head.direction = Direction::Down;
-    } else if
-        !keys.any_pressed([KeyCode::Up, KeyCode::W]) &&
-        !keys.any_pressed([KeyCode::Right, KeyCode::D]) && 
-        keys.any_pressed([KeyCode::Down, KeyCode::S]) && 
-        keys.any_pressed([KeyCode::Left, KeyCode::A]) {
-            input |= INPUT_DOWN_LEFT;
-    } else if
-        !keys.any_pressed([KeyCode::Up, KeyCode::W]) &&
-        !keys.any_pressed([KeyCode::Right, KeyCode::D]) && 
-        !keys.any_pressed([KeyCode::Down, KeyCode::S]) && 
-        keys.any_pressed([KeyCode::Left, KeyCode::A]) {
-            input |= INPUT_LEFT;
-    } else if
-        keys.any_pressed([KeyCode::Up, KeyCode::W]) &&
-        !keys.any_pressed([KeyCode::Right, KeyCode::D]) && 
-        !keys.any_pressed([KeyCode::Down, KeyCode::S]) && 
-        keys.any_pressed([KeyCode::Left, KeyCode::A]) {
-            input |= INPUT_UP_LEFT;
-    } else if
-        keys.pressed(KeyCode::Space) {
-            // head.direction = Direction::None;
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bytemuck::{Pod, Zeroable};
+
+use crate::{
+    ai::{bot_input, BotSenses},
+    bindings::{GamepadAssignments, KeyBindings, ACTION_KEYS},
+    components::Direction,
+    settings::{MatchSettings, SessionMode, SessionSettings},
+};
+
+/// What a player does in one frame, the rollback input GGRS sends to the
+/// other peers as plain bytes
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Pod, Zeroable)]
+pub struct SnakeInput {
+    /// `SnakeInput::VERSION` of the client that encoded it
+    pub version: u8,
+    /// index into `Direction::ALL` plus one, 0 for none
+    pub direction: u8,
+    /// `SnakeInput::BOOST` and the other flags
+    pub flags: u8,
+}
+
+impl SnakeInput {
+    /// Bump whenever the fields change layout or meaning, peers with
+    /// another version are refused in the lobby
+    pub const VERSION: u8 = 1;
+
+    /// move at double speed while held
+    pub const BOOST: u8 = 1 << 0;
+    /// reserved for items, nothing uses it yet
+    pub const USE_ITEM: u8 = 1 << 1;
+    /// once every player holds it the round over screen is skipped
+    pub const READY: u8 = 1 << 2;
+    /// turn 60° counter-clockwise, once per press
+    pub const TURN_LEFT: u8 = 1 << 3;
+    /// turn 60° clockwise, once per press
+    pub const TURN_RIGHT: u8 = 1 << 4;
+    pub const TURNS: u8 = Self::TURN_LEFT | Self::TURN_RIGHT;
+
+    pub const NONE: SnakeInput = SnakeInput { version: Self::VERSION, direction: 0, flags: 0 };
+
+    pub fn steer(direction: Direction) -> Self {
+        let direction = Direction::ALL.iter().position(|d| *d == direction).map_or(0, |i| i as u8 + 1);
+        Self { direction, ..Self::NONE }
+    }
+
+    pub fn with_flags(mut self, flags: u8) -> Self {
+        self.flags |= flags;
+        self
+    }
+
+    /// Direction to steer in. Inputs of another version, like the zeroed
+    /// ones GGRS starts with, steer nowhere.
+    pub fn direction(&self) -> Direction {
+        match self.version {
+            Self::VERSION => (self.direction as usize)
+                .checked_sub(1)
+                .and_then(|i| Direction::ALL.get(i).copied())
+                .unwrap_or(Direction::None),
+            _ => Direction::None,
+        }
+    }
+
+    /// Flags held this frame, none for inputs of another version
+    pub fn flags(&self) -> u8 {
+        match self.version {
+            Self::VERSION => self.flags,
+            _ => 0,
+        }
+    }
+
+    pub fn holds(&self, flag: u8) -> bool {
+        self.flags() & flag != 0
+    }
+
+    /// Combine the input of two devices: the first direction wins, flags add up
+    pub fn or(self, other: SnakeInput) -> SnakeInput {
+        let direction = if self.direction() != Direction::None { self.direction() } else { other.direction() };
+        SnakeInput::steer(direction).with_flags(self.flags() | other.flags())
+    }
+}
+
+/// Which of up, right, down and left are held. Diagonal hex directions take
+/// two keys, up or down alone do not map to a direction.
+pub(crate) fn chord(up: bool, right: bool, down: bool, left: bool) -> Direction {
+    match (up, right, down, left) {
+        (true, true, false, false) => Direction::UpRight,
+        (false, true, false, false) => Direction::Right,
+        (false, true, true, false) => Direction::DownRight,
+        (false, false, true, true) => Direction::DownLeft,
+        (false, false, false, true) => Direction::Left,
+        (true, false, false, true) => Direction::UpLeft,
+        _ => Direction::None,
+    }
+}
+
+pub(crate) fn turn_flags(left: bool, right: bool) -> u8 {
+    (left as u8 * SnakeInput::TURN_LEFT) | (right as u8 * SnakeInput::TURN_RIGHT)
+}
+
+/// Direction picked by a tap or swipe on a touch screen or with the mouse,
+/// steering once. Online it steers the local player, otherwise player 1.
+#[derive(Default)]
+pub struct PointerSteering {
+    pub pending: Option<Direction>,
+}
+
+impl PointerSteering {
+    pub fn steers(handle: usize, session: &SessionSettings) -> bool {
+        session.mode == SessionMode::Online || handle == 0
+    }
+}
+
+/// Connected gamepads and what is held on them, absent without an input plugin
+#[derive(SystemParam)]
+pub struct GamepadInput<'w, 's> {
+    gamepads: Option<Res<'w, Gamepads>>,
+    buttons: Option<Res<'w, Input<GamepadButton>>>,
+    axes: Option<Res<'w, Axis<GamepadAxis>>>,
+    assignments: Res<'w, GamepadAssignments>,
+    #[system_param(ignore)]
+    marker: std::marker::PhantomData<&'s ()>,
+}
+
+impl<'w, 's> GamepadInput<'w, 's> {
+    /// D-pad chords like the keyboard, otherwise the direction the left
+    /// stick points in once it leaves the deadzone. The shoulder buttons
+    /// turn, South boosts, West uses an item and Start is ready.
+    fn input(&self, gamepad: Gamepad, deadzone: f32) -> SnakeInput {
+        let (buttons, axes) = match (&self.buttons, &self.axes) {
+            (Some(buttons), Some(axes)) => (buttons, axes),
+            _ => return SnakeInput::NONE,
+        };
+        let held = |button_type| buttons.pressed(GamepadButton::new(gamepad, button_type));
+        let flags = turn_flags(held(GamepadButtonType::LeftTrigger), held(GamepadButtonType::RightTrigger))
+            | held(GamepadButtonType::South) as u8 * SnakeInput::BOOST
+            | held(GamepadButtonType::West) as u8 * SnakeInput::USE_ITEM
+            | held(GamepadButtonType::Start) as u8 * SnakeInput::READY;
+
+        let dpad = chord(
+            held(GamepadButtonType::DPadUp),
+            held(GamepadButtonType::DPadRight),
+            held(GamepadButtonType::DPadDown),
+            held(GamepadButtonType::DPadLeft),
+        );
+        let axis = |axis_type| axes.get(GamepadAxis::new(gamepad, axis_type)).unwrap_or(0.);
+        let stick = Vec2::new(axis(GamepadAxisType::LeftStickX), axis(GamepadAxisType::LeftStickY));
+        let direction = if dpad != Direction::None || stick.length() <= deadzone {
+            dpad
+        } else {
+            Direction::from_angle(stick.y.atan2(stick.x))
+        };
+        SnakeInput::steer(direction).with_flags(flags)
+    }
+
+    fn connected(&self) -> Vec<Gamepad> {
+        let mut pads: Vec<Gamepad> = self.gamepads.iter().flat_map(|gamepads| gamepads.iter().copied()).collect();
+        pads.sort_by_key(|gamepad| gamepad.id);
+        pads
+    }
+
+    /// The gamepad assigned to `handle`, else the unassigned gamepads go in
+    /// id order to the handles after the keyboard players
+    fn for_player(&self, handle: usize, keyboard_players: usize) -> Option<Gamepad> {
+        if let Some(gamepad) = self.assignments.get(handle) {
+            return Some(gamepad);
+        }
+        let free: Vec<Gamepad> =
+            self.connected().into_iter().filter(|gamepad| !self.assignments.is_assigned(*gamepad)).collect();
+        handle.checked_sub(keyboard_players).and_then(|i| free.get(i).copied())
+    }
+}
+
+/// Online the local player steers with any of the layouts in `KeyBindings`
+/// and any gamepad. When every player sits at this machine, the first players
+/// get one layout each and the gamepads in `GamepadAssignments` go to their
+/// players, remaining gamepads to the players after the keyboard ones. Bots
+/// take the highest handles. A tap or swipe, see `PointerSteering`, wins
+/// over everything else. Without an input plugin, e.g. when running
+/// headless, only bots move.
+#[allow(clippy::too_many_arguments)]
+pub fn input(
+    handle: In<ggrs::PlayerHandle>,
+    keys: Option<Res<Input<KeyCode>>>,
+    pads: GamepadInput,
+    mut pointer: ResMut<PointerSteering>,
+    bindings: Res<KeyBindings>,
+    settings: Res<MatchSettings>,
+    session: Res<SessionSettings>,
+    senses: BotSenses,
+) -> SnakeInput {
+    if session.is_bot(handle.0, &settings) {
+        return bot_input(handle.0, session.difficulty, &senses);
+    }
+
+    let pointer = if PointerSteering::steers(handle.0, &session) {
+        pointer.pending.take().map_or(SnakeInput::NONE, SnakeInput::steer)
+    } else {
+        SnakeInput::NONE
+    };
+
+    let keys = match keys {
+        Some(keys) => keys,
+        None => return pointer,
+    };
+    let deadzone = session.stick_deadzone;
+    let keyboard = |player: usize| {
+        let actions = ACTION_KEYS.get(player).map_or(0, |actions| actions.flags(&keys));
+        bindings.players[player].input(&keys).with_flags(actions)
+    };
+
+    if session.mode == SessionMode::Online {
+        return (0..bindings.players.len())
+            .map(keyboard)
+            .chain(pads.connected().into_iter().map(|gamepad| pads.input(gamepad, deadzone)))
+            .fold(pointer, SnakeInput::or);
     }
 
-    input
-}
\ No newline at end of file
+    let keyboard = if handle.0 < bindings.players.len() { keyboard(handle.0) } else { SnakeInput::NONE };
+    let gamepad = pads
+        .for_player(handle.0, bindings.players.len())
+        .map_or(SnakeInput::NONE, |gamepad| pads.input(gamepad, deadzone));
+    pointer.or(keyboard).or(gamepad)
+}
@@ -0,0 +1,59 @@
+use ggrs::{PlayerType, SessionBuilder, SyncTestSession};
+
+use settings::{MatchSettings, SessionMode, SessionSettings};
+
+pub mod ai;
+pub mod bindings;
+pub mod checksum;
+pub mod components;
+mod hash;
+pub mod hex;
+pub mod input;
+pub mod replay;
+pub mod settings;
+mod sim;
+
+pub use sim::*;
+
+/// Default rollback frames per second, see `MatchSettings::fps`
+pub const FPS: usize = 60;
+
+/// Frames between two steps of a head at `FPS`, 750ms
+pub const MOVEMENT_INTERVAL: u32 = 45;
+
+pub struct GgrsConfig;
+
+impl ggrs::Config for GgrsConfig {
+    type Input = input::SnakeInput;
+    type State = u8;
+    // Matchbox's WebRtcSocket addresses are strings
+    type Address = String;
+}
+
+/// GGRS session with every player on this machine. In sync test mode it
+/// rolls back and resimulates `check_distance` frames every frame and reports
+/// checksum mismatches of the rollback state, other modes never roll back.
+pub fn local_session(settings: &MatchSettings, session: &SessionSettings) -> SyncTestSession<GgrsConfig> {
+    let check_distance = match session.mode {
+        SessionMode::SyncTest => session.check_distance,
+        _ => 0,
+    };
+
+    let mut session_builder = SessionBuilder::<GgrsConfig>::new()
+        .with_num_players(settings.num_players)
+        .with_check_distance(check_distance)
+        .with_input_delay(settings.input_delay)
+        .with_max_prediction_window(settings.max_prediction)
+        .with_fps(settings.fps)
+        .expect("invalid fps");
+
+    for handle in 0..settings.num_players {
+        session_builder = session_builder
+            .add_player(PlayerType::Local, handle)
+            .expect("failed to add player");
+    }
+
+    session_builder
+        .start_synctest_session()
+        .expect("failed to start session")
+}
@@ -1,121 +1,109 @@
-use bevy::{prelude::*, tasks::IoTaskPool};
+use std::collections::{HashMap, HashSet};
+
+use bevy::{app::AppExit, ecs::system::SystemParam, prelude::*, tasks::IoTaskPool};
 use bevy_ggrs::*;
-use ggrs::InputStatus;
+use ggrs::PlayerType;
 use rand::{thread_rng, Rng};
-use bevy::input::keyboard::KeyboardInput;
 use matchbox_socket::WebRtcSocket;
-use input::*;
-use components::{*, Direction};
-use std::time::Duration;
-mod components;
-mod input;
-
-struct GgrsConfig;
-
-impl ggrs::Config for GgrsConfig {
-    // 6-directions
-    type Input = u8;
-    type State = u8;
-    // Matchbox's WebRtcSocket addresses are strings
-    type Address = String;
-}
+use bevy_hex::{bindings::{GamepadAssignments, KeyBindings}, checksum::*, components::*, input::SnakeInput, replay::*, settings::*, *};
 
-#[derive(Clone, Eq, PartialEq, Debug, Hash)]
-enum GameState {
-    Matchmaking,
-    InGame,
-}
+mod camera;
+mod net;
+mod pointer;
+mod rebind;
+mod ui;
 
-#[derive(SystemLabel, Debug, Clone, Hash, Eq, PartialEq)]
-enum Systems {
-    ActionSystem,
-    HeadMovement,
-    HexToPixel,
-    HeadCrumpleCollision,
-    SpawnSegment,
-    SpawnCrumple,
-    UpdateFollower,
-    OnUpdateFollower,
-}
+use camera::spectator_camera;
+use rebind::{load_bindings, RebindPlugin};
+use pointer::{clear_pointer_steering, pointer_steering};
+use net::{ConnectionStatus, DesyncPlugin, MultiplexSocket, PeerChannel, PeerMessage, PeerRole, SessionEventsPlugin};
+use ui::UiPlugin;
 
-struct CrumpleHandle(Handle<Image>);
+/// Distance from the center of a hex to its corners in pixels
+const HEX_SIZE: f32 = 105.;
 
-struct UpdateFollower {
-    entity: Entity,
-    hex: Hex
+/// What the local app is showing. Once a session runs the in-match states
+/// follow the rollback `MatchPhase`, so all peers go through them together.
+#[derive(Clone, Eq, PartialEq, Debug, Hash)]
+pub enum GameState {
+    MainMenu,
+    Rebind,
+    Lobby,
+    Countdown,
+    Playing,
+    RoundOver,
+    MatchOver,
 }
 
-#[derive(Default, Reflect, Component)]
-struct SpawnCrumple();
-
-struct UpdateBody(Entity, Entity);
-
-struct SpawnSegment(Entity, Entity);
-
-#[derive(Default)]
-struct WorldSize(isize);
+struct SpriteHandles {
+    snake: Handle<Image>,
+    crumple: Handle<Image>,
+}
 
 fn main() {
     let mut app = App::new();
-
-    GGRSPlugin::<GgrsConfig>::new()
-        .with_input_system(input::input)
-        .with_rollback_schedule(Schedule::default()
-            .with_stage(
-                "action",
-                SystemStage::single_threaded()
-                .with_system(action_system.label(Systems::ActionSystem))
-                .with_system(head_movement.label(Systems::HeadMovement).after(Systems::ActionSystem))
-                .with_system(update_body)
-                .with_system(hex_to_pixel.label(Systems::HexToPixel).after(Systems::HeadMovement))
-                .with_system(head_crumple_collision.label(Systems::HeadCrumpleCollision).after(Systems::HeadMovement))
-                .with_system(spawn_segment.label(Systems::SpawnSegment).after(Systems::HeadCrumpleCollision))
-                .with_system(spawn_crumple.label(Systems::SpawnCrumple).after(Systems::HeadCrumpleCollision))
-                .with_system(update_follower.label(Systems::UpdateFollower).after(Systems::HeadMovement))
-                .with_system(on_update_follower.label(Systems::OnUpdateFollower).after(Systems::UpdateFollower))
-            )
-            // .with_stage(
-            //     "ROLLBACK_STAGE",
-            //     SystemStage::single_threaded()
-            //     .with_system(head_movement)
-            //     .with_run_criteria(FixedTimestep::step(0.75))
-            // )
-        )
-        .register_rollback_type::<Head>()
-        .register_rollback_type::<Tail>()
-        .register_rollback_type::<Transform>()
-        .register_rollback_type::<Hex>()
-        .register_rollback_type::<Pcg32RandomT>()
-        .register_rollback_type::<Crumple>()
-        .register_rollback_type::<Segment>()
-        .register_rollback_type::<MovementCooldown>()
-        .register_rollback_type::<Body>()
-        .register_rollback_type::<SpawnCrumple>()
-        .build(&mut app);
+    // first, so the options, bindings and replay loaded below can log
+    app.add_plugins(DefaultPlugins);
+    let options = LaunchOptions::load();
+    let mut settings = MatchSettings::from_options(&options);
+    let connection = ConnectionSettings::from_options(&options, &settings);
+    let session = SessionSettings::from_options(&options, &settings);
+
+    if session.mode == SessionMode::Replay {
+        // the recorded rules win over the ones passed on the command line
+        match load_replay(&session.replay_file) {
+            Ok(replay) => {
+                settings = replay.settings.clone();
+                app.insert_resource(replay);
+            }
+            Err(err) => {
+                error!("failed to load replay {}: {}", session.replay_file, err);
+                app.insert_resource(MenuNotice(format!("Could not load {}", session.replay_file)));
+            }
+        }
+    }
 
     app
-        .add_state(GameState::Matchmaking)
-        .add_plugins(DefaultPlugins)
+        .insert_resource(load_bindings(&session))
+        .insert_resource(settings)
+        .insert_resource(connection)
+        .insert_resource(session.clone())
+        .add_state(GameState::MainMenu)
+        .add_plugin(SnakeSimPlugin)
+        .add_plugin(UiPlugin)
+        .add_plugin(DesyncPlugin)
+        .add_plugin(SessionEventsPlugin)
+        .add_plugin(RebindPlugin)
         .add_plugin(bevy::diagnostic::FrameTimeDiagnosticsPlugin)
         .add_plugin(bevy::diagnostic::EntityCountDiagnosticsPlugin)
-        .insert_resource(WorldSize(4))
         .insert_resource(bevy::ecs::schedule::ReportExecutionOrderAmbiguities)
         .add_startup_system(generate_map)
-        .add_system_set(
-            SystemSet::on_enter(GameState::Matchmaking)
-                .with_system(start_matchbox_socket)
-                .with_system(setup),
-        )
-        .add_startup_system(spawn_snake)
-        .add_system(keyboard_events)
-        .add_event::<UpdateFollower>()
-        .init_resource::<Events<SpawnCrumple>>()
-        .add_event::<SpawnSegment>()
-        .add_event::<UpdateBody>()
-        .add_system_set(SystemSet::on_update(GameState::Matchmaking).with_system(wait_for_players))
-        .add_system_set(SystemSet::on_enter(GameState::InGame).with_system(spawn_initial_crumple))
-        // .add_system_set(SystemSet::on_update(GameState::InGame).with_system(spawn_crumple))
-        .run();
+        .add_startup_system(load_sprites)
+        .add_startup_system(setup)
+        .add_system(add_sprites)
+        .add_system(hex_to_pixel)
+        .add_system(follow_match_phase)
+        .add_system(release_gamepads)
+        .add_system_set(SystemSet::on_update(GameState::MainMenu).with_system(main_menu))
+        .add_system_set(SystemSet::on_enter(GameState::Playing).with_system(clear_pointer_steering))
+        .add_system_set(SystemSet::on_update(GameState::Playing).with_system(pointer_steering))
+        .add_system_set(SystemSet::on_update(GameState::MatchOver).with_system(leave_match));
+
+    match session.mode {
+        SessionMode::Online => app
+            .add_system_set(SystemSet::on_enter(GameState::Lobby).with_system(start_matchbox_socket))
+            .add_system_set(SystemSet::on_update(GameState::Lobby).with_system(wait_for_players))
+            .add_system(spectator_camera)
+            .add_system(return_to_lobby),
+        SessionMode::SyncTest | SessionMode::Local => app
+            .add_system_set(SystemSet::on_update(GameState::MainMenu).with_system(claim_gamepads))
+            .add_system_set(SystemSet::on_enter(GameState::Lobby).with_system(start_local_session)),
+        SessionMode::Replay => app
+            .add_system_set(SystemSet::on_enter(GameState::Lobby).with_system(start_replay))
+            .add_system(replay_controls),
+    };
+
+    app.run();
 }
 
 fn generate_map(
@@ -123,14 +111,7 @@ fn generate_map(
     asset_server: Res<AssetServer>,
     world_size: Res<WorldSize>
 ) {
-    let mut map: Vec<Hex> = vec![];
-    for i in -world_size.0..=world_size.0 {
-        for j in -world_size.0..=world_size.0 {
-            if (i + j).abs() <= world_size.0 {
-                map.push(Hex { q: i as f32, r: j as f32, z: 0. });
-            }
-        }
-    }
+    let map = Hex::ZERO.spiral(world_size.radius);
     // let texture_handle = asset_server.load_folder("HK-Heightend Sensory Input v2/HSI - Indigo/").unwrap();
     let mut texture_handle = Vec::new();
     for i in 1..13 {
@@ -150,57 +131,45 @@ fn generate_map(
 
 fn setup(
     mut commands: Commands,
-    server: Res<AssetServer>
 ) {
     let mut camera = Camera2dBundle::default();
     camera.transform.scale = Vec3::new(3.0, 3.0, 1.0);
     commands.spawn_bundle(camera);
-    let handle: Handle<Image> = server.load("HK-Heightend Sensory Input v2/HSI - Icons/HSI - Icon Geometric Light/HSI_icon_109l.png");
-    commands.insert_resource(CrumpleHandle(handle));
 }
 
-fn spawn_snake(
+fn load_sprites(
     mut commands: Commands,
-    asset_server: Res<AssetServer>,
-    mut rip: ResMut<RollbackIdProvider>
+    server: Res<AssetServer>,
+) {
+    commands.insert_resource(SpriteHandles {
+        snake: server.load("HK-Heightend Sensory Input v2/HSI - Icons/HSI - Icon Geometric Light/HSI_icon_123l.png"),
+        crumple: server.load("HK-Heightend Sensory Input v2/HSI - Icons/HSI - Icon Geometric Light/HSI_icon_109l.png"),
+    });
+}
+
+/// The simulation only spawns logical entities, dress them up in sprites.
+/// Also covers entities that reappear when a rollback restores them.
+fn add_sprites(
+    mut commands: Commands,
+    handles: Res<SpriteHandles>,
+    query: Query<(Entity, &Hex, Option<&Crumple>), Or<(Added<Head>, Added<Segment>, Added<Crumple>)>>,
 ) {
-    let texture_handle = asset_server.load("HK-Heightend Sensory Input v2/HSI - Icons/HSI - Icon Geometric Light/HSI_icon_123l.png");
-
-    // player 1
-    let player_1 = commands.spawn_bundle(SpriteBundle {
-        texture: texture_handle.clone(),
-        ..Default::default()
-    })
-    .insert(Player { handle: 0 })
-    .insert(Rollback::new(rip.next_id()))
-    .insert(Hex { q: -2., r: 0., z: 1. })
-    .insert(HexHistory(Vec::new()))
-    .insert(Head { direction: Direction::None, last_direction: Direction::None })
-    .insert(Tail)
-    .insert(MovementCooldown { timer: Timer::new(Duration::from_millis(750), true)})
-    .id();
-
-    commands.entity(player_1).insert(Body(vec![player_1]));
-
-    // player 2
-    let player_2 = commands.spawn_bundle(SpriteBundle {
-        texture: texture_handle.clone(),
-        ..Default::default()
-    })
-    .insert(Player { handle: 1 })
-    .insert(Rollback::new(rip.next_id()))
-    .insert(Hex { q: 2., r: 0., z: 1. })
-    .insert(HexHistory(Vec::new()))
-    .insert(Head { direction: Direction::None, last_direction: Direction::None })
-    .insert(Tail)
-    .insert(MovementCooldown { timer: Timer::new(Duration::from_millis(750), true)})
-    .id();
-
-    commands.entity(player_2).insert(Body(vec![player_2]));
+    for (entity, hex, crumple) in query.iter() {
+        let texture = match crumple {
+            Some(_) => handles.crumple.clone(),
+            None => handles.snake.clone(),
+        };
+        let pixel = hex.to_pixel(HEX_SIZE);
+        commands.entity(entity).insert_bundle(SpriteBundle {
+            texture,
+            transform: Transform::from_xyz(pixel.x, pixel.y, 1.),
+            ..Default::default()
+        });
+    }
 }
 
-fn start_matchbox_socket(mut commands: Commands) {
-    let room_url = "ws://127.0.0.1:3536/next_2";
+fn start_matchbox_socket(mut commands: Commands, connection: Res<ConnectionSettings>) {
+    let room_url = connection.room_url();
     info!("connecting to matchbox server: {:?}", room_url);
     let (socket, message_loop) = WebRtcSocket::new(room_url);
 
@@ -209,58 +178,224 @@ fn start_matchbox_socket(mut commands: Commands) {
     let task_pool = IoTaskPool::get();
     task_pool.spawn(message_loop).detach();
 
-    commands.insert_resource(Some(socket));
+    commands.insert_resource(Some(MultiplexSocket::new(socket)));
+}
+
+/// Enter starts a match, or the playback of a replay if one was loaded
+fn main_menu(
+    mut keys: ResMut<Input<KeyCode>>,
+    mut state: ResMut<State<GameState>>,
+    mut exit: EventWriter<AppExit>,
+    session_settings: Res<SessionSettings>,
+    replay: Option<Res<Replay>>,
+) {
+    let playable = session_settings.mode != SessionMode::Replay || replay.is_some();
+    if keys.just_pressed(KeyCode::Return) && playable {
+        keys.reset(KeyCode::Return);
+        state.set(GameState::Lobby).unwrap();
+    } else if keys.just_pressed(KeyCode::B) {
+        state.set(GameState::Rebind).unwrap();
+    } else if keys.just_pressed(KeyCode::Escape) {
+        exit.send(AppExit);
+    }
+}
+
+/// South on a gamepad in the main menu gives it to the first player without
+/// a keyboard layout or gamepad, pressing it again moves it on to the next.
+fn claim_gamepads(
+    buttons: Res<Input<GamepadButton>>,
+    mut assignments: ResMut<GamepadAssignments>,
+    bindings: Res<KeyBindings>,
+    settings: Res<MatchSettings>,
+    session: Res<SessionSettings>,
+) {
+    let humans = settings.num_players - session.bots;
+    for button in buttons.get_just_pressed() {
+        if button.button_type != GamepadButtonType::South {
+            continue;
+        }
+        let current = (0..humans).find(|handle| assignments.get(*handle) == Some(button.gamepad));
+        let first = current.map_or(bindings.players.len(), |handle| handle + 1);
+        let free = (first..humans).find(|handle| assignments.get(*handle).is_none());
+        match free {
+            Some(handle) => assignments.assign(handle, button.gamepad),
+            None => assignments.unassign(button.gamepad),
+        }
+    }
+}
+
+fn release_gamepads(mut events: EventReader<GamepadEvent>, mut assignments: ResMut<GamepadAssignments>) {
+    for event in events.iter() {
+        if event.event_type == GamepadEventType::Disconnected {
+            assignments.unassign(event.gamepad);
+        }
+    }
 }
 
+/// Shown in the main menu after a trip to the lobby went wrong
+pub struct MenuNotice(pub String);
+
+/// Seconds a spectator waits for a match to start before giving up, the
+/// host of a match that already runs never answers
+const SPECTATE_TIMEOUT: f64 = 30.;
+
+/// Seconds between two hellos to the peers in the lobby, the channel may
+/// drop any of them
+const HELLO_INTERVAL: f64 = 1.;
+
+/// Introduce ourselves to every peer in the room and start once enough of
+/// them want to play. Player handles follow the sorted peer ids, players
+/// beyond `num_players` watch instead. The player with handle 0 hosts every
+/// spectator that said hello before the start. Peers encoding another
+/// `SnakeInput::VERSION` or playing by other `MatchSettings` rules are left out.
+#[allow(clippy::too_many_arguments)]
 fn wait_for_players(
     mut commands: Commands, 
-    mut socket: ResMut<Option<WebRtcSocket>>,
-    mut state: ResMut<State<GameState>>, 
+    mut socket: ResMut<Option<MultiplexSocket>>,
+    mut roles: Local<HashMap<String, PeerRole>>,
+    mut refused: Local<HashSet<String>>,
+    mut waiting_since: Local<Option<f64>>,
+    mut last_hello: Local<f64>,
+    time: Res<Time>,
+    mut state: ResMut<State<GameState>>,
+    settings: Res<MatchSettings>,
+    session_settings: Res<SessionSettings>,
 ) {
-    let socket = socket.as_mut();
-
     // If there is no socket we've already started the game
-    if socket.is_none() {
+    let lobby = match socket.as_mut() {
+        Some(lobby) => lobby,
+        None => return,
+    };
+
+    let now = time.seconds_since_startup();
+    let waited = now - *waiting_since.get_or_insert(now);
+    if session_settings.spectate && waited > SPECTATE_TIMEOUT {
+        error!("no match started within {} seconds, it may already be running", SPECTATE_TIMEOUT);
+        commands.insert_resource(MenuNotice("No match to watch in this room".to_string()));
+        *socket = None;
+        *waiting_since = None;
+        roles.clear();
+        refused.clear();
+        state.set(GameState::MainMenu).unwrap();
         return;
     }
 
-    // Check for new connections
-    socket.as_mut().unwrap().accept_new_connections();
-    let players = socket.as_ref().unwrap().players();
+    let own_role = if session_settings.spectate { PeerRole::Spectator } else { PeerRole::Player };
+    let hello = PeerMessage::Hello {
+        role: own_role,
+        input_version: SnakeInput::VERSION,
+        rules_hash: settings.rules_hash(),
+    };
+    let new_peers = lobby.accept_new_connections();
+    // greet new peers right away and everyone again until the match starts,
+    // a peer that missed our hello would wait for it forever
+    let hello_peers = if now - *last_hello >= HELLO_INTERVAL {
+        *last_hello = now;
+        lobby.connected_peers()
+    } else {
+        new_peers
+    };
+    for peer in hello_peers {
+        lobby.channel().send(&peer, &hello);
+    }
+    lobby.poll_lobby();
+    for (peer, message) in lobby.channel().receive() {
+        if let PeerMessage::Hello { role, input_version, rules_hash } = message {
+            if input_version != SnakeInput::VERSION {
+                warn!(
+                    "refusing {}, it encodes input version {} instead of {}",
+                    peer, input_version, SnakeInput::VERSION
+                );
+                refused.insert(peer);
+                continue;
+            }
+            if rules_hash != settings.rules_hash() {
+                warn!("refusing {}, it plays with other players, rounds, radius, boundary or fps", peer);
+                refused.insert(peer);
+                continue;
+            }
+            info!("{} joined as {:?}", peer, role);
+            roles.insert(peer, role);
+        }
+    }
+    let connected = lobby.connected_peers();
+    roles.retain(|peer, _| connected.contains(peer));
+    refused.retain(|peer| connected.contains(peer));
+
+    let self_id = lobby.id().clone();
+    let mut player_ids: Vec<String> = roles
+        .iter()
+        .filter(|(_, role)| **role == PeerRole::Player)
+        .map(|(peer, _)| peer.clone())
+        .collect();
+    if own_role == PeerRole::Player {
+        player_ids.push(self_id.clone());
+    }
+    player_ids.sort();
 
-    let num_players = 2;
-    if players.len() < num_players {
+    let num_players = settings.num_players;
+    if player_ids.len() < num_players {
         return; // wait for more players
     }
+    if roles.len() + refused.len() < connected.len() {
+        return; // wait to hear whether the others play or watch
+    }
+    let mut spectator_ids: Vec<String> = roles
+        .drain()
+        .filter(|(_, role)| *role == PeerRole::Spectator)
+        .map(|(peer, _)| peer)
+        .collect();
+    if player_ids.len() > num_players {
+        warn!("{} players in the room, the last {} watch", player_ids.len(), player_ids.len() - num_players);
+        spectator_ids.extend(player_ids.split_off(num_players));
+    }
+    spectator_ids.sort();
+    let own_role = if spectator_ids.contains(&self_id) { PeerRole::Spectator } else { own_role };
+    let host = player_ids[0].clone();
+    *waiting_since = None;
+    refused.clear();
 
-    info!("All peers have joined, going in-game");
+    info!("All peers have joined, starting the match");
 
-    // create a GGRS P2P session
+    // rng seed, derived from the ids of all players so every peer ends up with the same one
+    let rng = Pcg32RandomT::from_peer_ids(player_ids.iter().map(String::as_str));
+    commands.insert_resource(rng);
+    commands.insert_resource(ReplayRecorder(Replay::new(rng, settings.clone())));
+
+    // move the socket out of the resource (required because GGRS takes ownership of it)
+    let socket = socket.take().unwrap();
     let mut session_builder = ggrs::SessionBuilder::<GgrsConfig>::new()
         .with_num_players(num_players)
-        .with_input_delay(2);
+        .with_input_delay(settings.input_delay)
+        .with_max_prediction_window(settings.max_prediction)
+        .with_fps(settings.fps)
+        .expect("invalid fps");
+
+    if own_role == PeerRole::Spectator {
+        info!("watching the match hosted by {}", host);
+        let session = session_builder.start_spectator_session(host, socket);
+        commands.insert_resource(session);
+        commands.insert_resource(SessionType::SpectatorSession);
+        return;
+    }
 
-    for (i, player) in players.into_iter().enumerate() {
+    let remote_players = player_ids.iter().filter(|id| **id != self_id).cloned().collect();
+    commands.insert_resource(socket.channel().with_peers(remote_players));
+
+    // create a GGRS P2P session
+    for (handle, id) in player_ids.into_iter().enumerate() {
+        let player = if id == self_id { PlayerType::Local } else { PlayerType::Remote(id) };
         session_builder = session_builder
-            .add_player(player, i)
+            .add_player(player, handle)
             .expect("failed to add player");
     }
-
-    // move the socket out of the resource (required because GGRS takes ownership of it)
-    let socket = socket.take().unwrap();
-
-    // rng seed
-    let connected_peers = socket.connected_peers();
-
-    let peer_id = connected_peers[0].clone().chars().filter(|c| c.is_digit(10)).collect::<String>().parse::<u128>().unwrap();
-    let self_id = socket.id().chars().filter(|c| c.is_digit(10)).collect::<String>().parse::<u128>().unwrap();
-    info!("peer id: {}", peer_id);
-    info!("peer id: {}", self_id);
-    let seed = u64::wrapping_mul(peer_id as u64, self_id as u64) as u64;
-    info!("{}", seed);
-
-    let rng = Pcg32RandomT::new(seed, 1);
-    commands.insert_resource(rng);
+    if host == self_id {
+        for (i, id) in spectator_ids.into_iter().enumerate() {
+            session_builder = session_builder
+                .add_player(PlayerType::Spectator(id), num_players + i)
+                .expect("failed to add spectator");
+        }
+    }
 
     // start the GGRS session
     let session = session_builder
@@ -269,240 +404,197 @@ fn wait_for_players(
     
     commands.insert_resource(session);
     commands.insert_resource(SessionType::P2PSession);
-
-    state.set(GameState::InGame).unwrap();
 }
 
-/// Convert hex to pixel
-/// size = distance from center to corner
-/// x = size * (sqrt(3) * hex.q + sqrt(3)/2 * hex.r)
-/// y = size * ( 3./2 * hex.r)
-fn hex_to_pixel(
-    mut query: Query<(&Hex, &mut Transform), Changed<Hex>>,
+/// Run every player locally, see `local_session`
+fn start_local_session(
+    mut commands: Commands,
+    settings: Res<MatchSettings>,
+    session_settings: Res<SessionSettings>,
 ) {
-    for (hex, mut transform) in query.iter_mut() {
-        transform.translation = Vec3::new(
-            105. * (3.0_f32.sqrt() * hex.q + (3.0_f32).sqrt() / 2. * hex.r),
-            -105. * (3./2. * hex.r),
-            hex.z,
-        )
-    }
+    info!("starting {:?} session", session_settings.mode);
+
+    let rng = Pcg32RandomT::new(session_settings.seed, 1);
+    commands.insert_resource(rng);
+    commands.insert_resource(ReplayRecorder(Replay::new(rng, settings.clone())));
+    commands.insert_resource(local_session(&settings, &session_settings));
+    commands.insert_resource(SessionType::SyncTestSession);
 }
 
-fn action_system(
-    inputs: Res<Vec<(u8, InputStatus)>>,
-    mut query: Query<(&mut Head, &Player)>
-) {
-    for (mut head, player) in query.iter_mut() {
-        let (input, _) = inputs[player.handle];
-
-        match input {
-            INPUT_UP_RIGHT => {
-                head.direction = Direction::UpRight;
-            },
-            INPUT_RIGHT => {
-                head.direction = Direction::Right;
-            },
-            INPUT_DOWN_RIGHT => {
-                head.direction = Direction::DownRight;
-            },
-            INPUT_DOWN_LEFT => {
-                head.direction = Direction::DownLeft;
-            },
-            INPUT_LEFT => {
-                head.direction = Direction::Left;
-            },
-            INPUT_UP_LEFT => {
-                head.direction = Direction::UpLeft;
-            },
-            _ => (),
-        }
-    }
+#[cfg(not(target_arch = "wasm32"))]
+fn load_replay(path: &str) -> Result<Replay, String> {
+    Replay::load(path).map_err(|err| err.to_string())
 }
 
-fn head_movement(
-    mut query: Query<(&mut Hex, &mut Head, &mut HexHistory, &mut MovementCooldown)>,
-    time: Res<Time>,
-) {
-    for (mut hex, mut head, mut hex_history, mut movement_cooldown) in query.iter_mut() {
-        movement_cooldown.timer.tick(time.delta());
-        if movement_cooldown.timer.finished() {
-            hex_history.0.push(hex.clone());
-            match head.direction {
-                components::Direction::UpRight => {
-                    hex.q += 1.;
-                    hex.r -= 1.;
-                },
-                components::Direction::Right => {
-                    hex.q += 1.;
-                },
-                components::Direction::DownRight => {
-                    hex.r += 1.;
-                },
-                components::Direction::DownLeft => {
-                    hex.q -= 1.;
-                    hex.r += 1.;
-                },
-                Direction::Left => {
-                    hex.q -= 1.;
-                },
-                Direction::UpLeft => {
-                    hex.r -= 1.;
-                },
-                Direction::None => (),
-            }
-            head.last_direction = head.direction;
-        }
-    }
+#[cfg(target_arch = "wasm32")]
+fn load_replay(_path: &str) -> Result<Replay, String> {
+    Err("replays can not be loaded in the browser".to_string())
 }
 
-fn keyboard_events(
-    mut key_evr: EventReader<KeyboardInput>,
-) {
-    use bevy::input::ButtonState;
-    for ev in key_evr.iter() {
-        match ev.state {
-            ButtonState::Pressed => {
-                println!("Key press: {:?} ({})", ev.key_code, ev.scan_code);
-            }
-            ButtonState::Released => {
-                println!("Key release: {:?} ({})", ev.key_code, ev.scan_code);
-            }
-        }
-    }
+/// Play the loaded replay back through the rollback schedule, no GGRS session involved
+fn start_replay(mut commands: Commands, replay: Res<Replay>) {
+    info!("playing back {} frames", replay.inputs.len());
+    commands.insert_resource(replay.rng);
+    commands.insert_resource(ReplayPlayback::new(replay.clone()));
 }
 
-fn spawn_initial_crumple(
-    mut spawn_crumple: EventWriter<SpawnCrumple>,
+/// Space pauses, `.` steps a single frame while paused, up and down change
+/// the speed. Replays of matches that were cut short end before the match
+/// does, once their inputs ran out Enter or Esc leave for the main menu.
+fn replay_controls(
+    mut keys: ResMut<Input<KeyCode>>,
+    playback: Option<ResMut<ReplayPlayback>>,
+    frame: Res<FrameCount>,
+    mut state: ResMut<State<GameState>>,
+    mut teardown: MatchTeardown,
 ) {
-    info!("spawning initial crumple");
-    spawn_crumple.send(SpawnCrumple());
+    let mut playback = match playback {
+        Some(playback) => playback,
+        None => return,
+    };
+    // a finished match is left through `leave_match`
+    let leave = keys.just_pressed(KeyCode::Return) || keys.just_pressed(KeyCode::Escape);
+    if leave && playback.finished(&frame) && *state.current() != GameState::MatchOver {
+        // the main menu would take the same press as a request to play again or quit
+        keys.reset(KeyCode::Return);
+        keys.reset(KeyCode::Escape);
+        teardown.end_match();
+        state.set(GameState::MainMenu).unwrap();
+        return;
+    }
+    if keys.just_pressed(KeyCode::Space) {
+        playback.paused = !playback.paused;
+    }
+    if keys.just_pressed(KeyCode::Period) && playback.paused {
+        playback.steps += 1;
+    }
+    if keys.just_pressed(KeyCode::Up) {
+        playback.speed = (playback.speed * 2.0).min(8.0);
+    }
+    if keys.just_pressed(KeyCode::Down) {
+        playback.speed = (playback.speed / 2.0).max(0.125);
+    }
 }
 
-fn spawn_crumple(
-    mut commands: Commands,
-    mut rip: ResMut<RollbackIdProvider>,
-    world_size: Res<WorldSize>,
-    mut spawn_crumple: EventReader<SpawnCrumple>,
-    handle: Res<CrumpleHandle>,
-    mut rng: ResMut<Pcg32RandomT>
-) {
-    for _ in spawn_crumple.iter() {
-        // random number between a set range = bounded_rand(max - min) + min
-        info!("rng: {}", rng.pcg32_random_r());
-        let q = (rng.pcg32_random_r() % 8) as f32 - 4.;
-        let r = (rng.pcg32_random_r() % 8) as f32 - 4.;
-        // let q = (rng.bounded_rand(8) - 4) as f32;
-        // let r = (rng.bounded_rand(8) - 4) as f32;
-        // let q = (rng.bounded_rand((world_size.0 - (-world_size.0)) as u32) + -world_size.0 as u32) as f32;
-        // let r = (rng.bounded_rand((world_size.0 - (-world_size.0)) as u32) + -world_size.0 as u32) as f32;
-        info!("q: {}, r: {}", q, r);
-        info!("spawning crumple");
-        commands.spawn_bundle(
-            SpriteBundle {
-                texture: handle.0.clone(),
-                ..Default::default()
-            }
-        )
-        .insert(Hex {
-            q: q,
-            r: r,
-            // q: (rand::thread_rng().gen_range(-world_size.0..=world_size.0)) as f32,
-            // r: (rand::thread_rng().gen_range(-world_size.0..=world_size.0)) as f32,
-            z: 1.
-        })
-        .insert(Crumple)
-        .insert(Rollback::new(rip.next_id()));
+#[cfg(not(target_arch = "wasm32"))]
+fn save_replay(replay: &Replay, path: &str) {
+    match replay.save(path) {
+        Ok(()) => info!("saved {} frames of replay to {}", replay.inputs.len(), path),
+        Err(err) => error!("failed to save replay to {}: {}", path, err),
     }
 }
 
-fn update_body(
-    mut query: Query<&mut Body>,
-    mut update_body: EventReader<UpdateBody>
+#[cfg(target_arch = "wasm32")]
+fn save_replay(_replay: &Replay, _path: &str) {}
+
+/// Mirror the rollback match phase into the local app state while a session runs
+fn follow_match_phase(
+    phase: Res<MatchPhase>,
+    session: Option<Res<SessionType>>,
+    playback: Option<Res<ReplayPlayback>>,
+    mut state: ResMut<State<GameState>>,
 ) {
-    for ev in update_body.iter() {
-        let mut body = query.get_mut(ev.0).unwrap();
-        body.0.push(ev.1);
+    if session.is_none() && playback.is_none() {
+        return;
+    }
+    let next = match *phase {
+        MatchPhase::Setup | MatchPhase::Countdown { .. } => GameState::Countdown,
+        MatchPhase::Playing => GameState::Playing,
+        MatchPhase::RoundOver { .. } => GameState::RoundOver,
+        MatchPhase::MatchOver => GameState::MatchOver,
+    };
+    if *state.current() != next {
+        // a rollback may change the phase again before the transition happened
+        let _ = state.set(next);
     }
 }
 
-fn spawn_segment(
-    mut commands: Commands,
-    mut rip: ResMut<RollbackIdProvider>,
-    asset_server: Res<AssetServer>,
-    mut query: Query<(Entity, &Hex)>,
-    mut spawn_segment: EventReader<SpawnSegment>,
-    mut update_body: EventWriter<UpdateBody>
-) {
-    for ev in spawn_segment.iter() {
-        info!("Spawning Segment");
-        let (entity, hex) = query.get_mut(ev.1).unwrap(); 
-        commands.entity(entity).remove::<Tail>();
-        let texture_handle = asset_server.load("HK-Heightend Sensory Input v2/HSI - Icons/HSI - Icon Geometric Light/HSI_icon_123l.png");
-        let follower = commands.spawn_bundle(SpriteBundle {
-            texture: texture_handle,
-            ..Default::default()
-        })
-        .insert(Hex { q: hex.q, r: hex.r, z: 1. })
-        .insert(Rollback::new(rip.next_id()))
-        .insert(HexHistory(Vec::new()))
-        .insert(Tail)
-        .insert(Following(entity))
-        .id();
-        commands.entity(entity).insert(Follower(follower));
-        update_body.send(UpdateBody(ev.0, follower));
+/// Everything needed to end a match, however it ended
+#[derive(SystemParam)]
+struct MatchTeardown<'w, 's> {
+    commands: Commands<'w, 's>,
+    recorder: Option<Res<'w, ReplayRecorder>>,
+    session_settings: Res<'w, SessionSettings>,
+    board_query: Query<'w, 's, Entity, Or<(With<Head>, With<Segment>, With<Crumple>)>>,
+}
+
+impl<'w, 's> MatchTeardown<'w, 's> {
+    /// Save the replay, drop the session and reset the simulation
+    fn end_match(&mut self) {
+        if let Some(recorder) = &self.recorder {
+            save_replay(&recorder.0, &self.session_settings.replay_file);
+        }
+        let commands = &mut self.commands;
+        for entity in self.board_query.iter() {
+            commands.entity(entity).despawn();
+        }
+        commands.remove_resource::<SessionType>();
+        commands.remove_resource::<ggrs::P2PSession<GgrsConfig>>();
+        commands.remove_resource::<ggrs::SyncTestSession<GgrsConfig>>();
+        commands.remove_resource::<ggrs::SpectatorSession<GgrsConfig>>();
+        commands.insert_resource(MatchPhase::default());
+        commands.insert_resource(MatchScore::default());
+        commands.insert_resource(RoundResult::default());
+        commands.insert_resource(FrameCount::default());
+        commands.insert_resource(ChecksumHistory::default());
+        commands.insert_resource(PendingMatchEvents::default());
+        commands.insert_resource(ConnectionStatus::default());
+        commands.remove_resource::<DesyncReport>();
+        commands.remove_resource::<PeerChannel>();
+        commands.remove_resource::<ReplayRecorder>();
+        commands.remove_resource::<ReplayPlayback>();
     }
 }
 
-fn update_follower(
-    query: Query<(&HexHistory, &Follower), Changed<Hex>>,
-    mut update_follower: EventWriter<UpdateFollower>
+/// Leave the finished match for the main menu
+fn leave_match(
+    mut keys: ResMut<Input<KeyCode>>,
+    mut state: ResMut<State<GameState>>,
+    mut teardown: MatchTeardown,
 ) {
-    for (hex_history, follower) in query.iter() {
-        let event = UpdateFollower {
-            entity: follower.0,
-            hex: hex_history.0.last().unwrap().clone()
-        };
-        update_follower.send(event);
+    if !keys.just_pressed(KeyCode::Return) {
+        return;
     }
+    // the main menu would take the same press as a request to play again
+    keys.reset(KeyCode::Return);
+    teardown.end_match();
+    state.set(GameState::MainMenu).unwrap();
 }
 
-fn on_update_follower(
-    mut query: Query<(&mut Hex, &mut HexHistory), With<Following>>,
-    mut follower_update: EventReader<UpdateFollower>
+/// Once a peer left, finish the round it forfeited and go back to the lobby
+/// to find new players. Spectators go back as soon as their host is gone.
+fn return_to_lobby(
+    status: Res<ConnectionStatus>,
+    session: Option<Res<SessionType>>,
+    mut state: ResMut<State<GameState>>,
+    mut teardown: MatchTeardown,
 ) {
-    for event in follower_update.iter() {
-        let entity = event.entity;
-        let (mut hex, mut hex_history) = query.get_mut(entity).unwrap();
-        hex_history.0.push(hex.clone());
-        let q = event.hex.q;
-        let r = event.hex.r;
-        hex.q = q;
-        hex.r = r;
+    if status.disconnected.is_empty() {
+        return;
+    }
+    let spectating = matches!(session.as_deref(), Some(SessionType::SpectatorSession));
+    let round_running = matches!(state.current(), GameState::Playing | GameState::RoundOver);
+    if round_running && !spectating {
+        return;
+    }
+    info!("{:?} disconnected, returning to the lobby", status.disconnected);
+    teardown.end_match();
+    // re-entering the lobby state opens a fresh socket
+    if *state.current() == GameState::Lobby {
+        let _ = state.restart();
+    } else {
+        let _ = state.set(GameState::Lobby);
     }
 }
 
-fn head_crumple_collision(
-    mut commands: Commands,
-    head_query: Query<(Entity, &Hex, &Body), With<Head>>,
-    crumple_query: Query<(Entity, &Hex), With<Crumple>>,
-    mut spawn_crumple: EventWriter<SpawnCrumple>,
-    mut spawn_segment: EventWriter<SpawnSegment>
+/// Place sprites at the pixel position of their hex, the z of the transform
+/// is left alone so render depth stays independent of the logical position
+fn hex_to_pixel(
+    mut query: Query<(&Hex, &mut Transform), Changed<Hex>>,
 ) {
-    for (entity_head, hex_head, body) in head_query.iter() {
-        for (crumple_entity, hex_crumple) in crumple_query.iter() {
-            if hex_head.q == hex_crumple.q && hex_head.r == hex_crumple.r {
-                info!("head crumple collision detected");
-                commands.entity(crumple_entity).despawn();
-                spawn_segment.send(SpawnSegment(entity_head, *body.0.last().unwrap()));
-                spawn_crumple.send(SpawnCrumple());
-            }
-        }
+    for (hex, mut transform) in query.iter_mut() {
+        let pixel = hex.to_pixel(HEX_SIZE);
+        transform.translation.x = pixel.x;
+        transform.translation.y = pixel.y;
     }
 }
-
-// fn rng_test(
-//     mut rng: ResMut<Pcg32RandomT>
-// ) {
-//     info!("{}", rng.pcg32_random_r());
-// }
\ No newline at end of file
@@ -0,0 +1,305 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use bevy::prelude::*;
+use bevy_hex::{checksum::*, settings::MatchSettings, GgrsConfig};
+use ggrs::{GGRSEvent, Message, NonBlockingSocket, P2PSession, SpectatorSession};
+use matchbox_socket::WebRtcSocket;
+
+/// First byte of every packet, telling GGRS traffic apart from our own
+const GGRS_PACKET: u8 = 0;
+const CHANNEL_PACKET: u8 = 1;
+
+/// What a peer in the room wants to do
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerRole {
+    Player,
+    Spectator,
+}
+
+/// Messages peers exchange next to the GGRS protocol
+#[derive(Clone, Debug)]
+pub enum PeerMessage {
+    /// sent to every peer on connecting, before any session starts.
+    /// `input_version` is the `SnakeInput::VERSION` the peer encodes with,
+    /// `rules_hash` the `MatchSettings::rules_hash` it plays by.
+    Hello { role: PeerRole, input_version: u8, rules_hash: u64 },
+    Checksum { frame: i32, checksum: Checksum },
+}
+
+impl PeerMessage {
+    const HELLO: u8 = 0;
+    const CHECKSUM: u8 = 1;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            PeerMessage::Hello { role, input_version, rules_hash } => {
+                let mut bytes = vec![Self::HELLO, (*role == PeerRole::Spectator) as u8, *input_version];
+                bytes.extend(rules_hash.to_le_bytes());
+                bytes
+            }
+            PeerMessage::Checksum { frame, checksum } => {
+                let mut bytes = vec![Self::CHECKSUM];
+                bytes.extend(frame.to_le_bytes());
+                bytes.extend(checksum.to_bytes());
+                bytes
+            }
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes.split_first()? {
+            (&Self::HELLO, [spectator, rest @ ..]) => Some(PeerMessage::Hello {
+                role: if *spectator != 0 { PeerRole::Spectator } else { PeerRole::Player },
+                // clients from before the version was sent
+                input_version: rest.first().copied().unwrap_or(0),
+                rules_hash: rest.get(1..9).and_then(|bytes| bytes.try_into().ok()).map_or(0, u64::from_le_bytes),
+            }),
+            (&Self::CHECKSUM, rest) if rest.len() >= 4 => Some(PeerMessage::Checksum {
+                frame: i32::from_le_bytes(rest[..4].try_into().ok()?),
+                checksum: Checksum::from_bytes(&rest[4..])?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queues {
+    outgoing: Vec<(String, Vec<u8>)>,
+    incoming: Vec<(String, PeerMessage)>,
+}
+
+/// Side channel to the other peers. GGRS owns the socket once the session
+/// started, so messages are queued here and exchanged whenever GGRS polls it.
+#[derive(Clone, Default)]
+pub struct PeerChannel {
+    pub peers: Vec<String>,
+    queues: Arc<Mutex<Queues>>,
+}
+
+impl PeerChannel {
+    /// Same queues, but broadcasting to `peers`
+    pub fn with_peers(&self, peers: Vec<String>) -> Self {
+        Self { peers, queues: self.queues.clone() }
+    }
+
+    pub fn send(&self, peer: &str, message: &PeerMessage) {
+        let mut packet = vec![CHANNEL_PACKET];
+        packet.extend(message.to_bytes());
+        self.queues.lock().unwrap().outgoing.push((peer.to_string(), packet));
+    }
+
+    pub fn broadcast(&self, message: &PeerMessage) {
+        for peer in &self.peers {
+            self.send(peer, message);
+        }
+    }
+
+    pub fn receive(&self) -> Vec<(String, PeerMessage)> {
+        std::mem::take(&mut self.queues.lock().unwrap().incoming)
+    }
+}
+
+/// Matchbox socket carrying both GGRS messages and the `PeerChannel`
+pub struct MultiplexSocket {
+    socket: WebRtcSocket,
+    channel: PeerChannel,
+}
+
+impl MultiplexSocket {
+    pub fn new(socket: WebRtcSocket) -> Self {
+        Self { socket, channel: default() }
+    }
+
+    pub fn channel(&self) -> &PeerChannel {
+        &self.channel
+    }
+
+    pub fn id(&self) -> &String {
+        self.socket.id()
+    }
+
+    pub fn accept_new_connections(&mut self) -> Vec<String> {
+        self.socket.accept_new_connections()
+    }
+
+    pub fn connected_peers(&self) -> Vec<String> {
+        self.socket.connected_peers()
+    }
+
+    /// Exchange `PeerChannel` messages while no session owns the socket yet.
+    /// GGRS messages of peers that already started are dropped, GGRS resends them.
+    pub fn poll_lobby(&mut self) {
+        self.receive_all_messages();
+    }
+
+    fn flush(&mut self) {
+        let outgoing = std::mem::take(&mut self.channel.queues.lock().unwrap().outgoing);
+        for (peer, packet) in outgoing {
+            self.socket.send(packet.into_boxed_slice(), peer);
+        }
+    }
+}
+
+impl NonBlockingSocket<String> for MultiplexSocket {
+    fn send_to(&mut self, msg: &Message, addr: &String) {
+        let mut packet = vec![GGRS_PACKET];
+        packet.extend(bincode::serialize(msg).expect("failed to serialize ggrs message"));
+        self.socket.send(packet.into_boxed_slice(), addr.clone());
+        self.flush();
+    }
+
+    fn receive_all_messages(&mut self) -> Vec<(String, Message)> {
+        self.flush();
+        let mut messages = Vec::new();
+        for (peer, packet) in self.socket.receive() {
+            match packet.split_first() {
+                Some((&GGRS_PACKET, rest)) => match bincode::deserialize(rest) {
+                    Ok(msg) => messages.push((peer, msg)),
+                    Err(err) => warn!("dropping malformed ggrs packet from {}: {}", peer, err),
+                },
+                Some((&CHANNEL_PACKET, rest)) => match PeerMessage::from_bytes(rest) {
+                    Some(message) => self.channel.queues.lock().unwrap().incoming.push((peer, message)),
+                    None => warn!("dropping malformed packet from {}", peer),
+                },
+                _ => warn!("dropping unknown packet from {}", peer),
+            }
+        }
+        messages
+    }
+}
+
+/// What GGRS told us about the other peers of the running session
+#[derive(Default)]
+pub struct ConnectionStatus {
+    /// peers we currently hear nothing from, they may still come back
+    pub interrupted: HashSet<String>,
+    /// peers that left the session for good
+    pub disconnected: HashSet<String>,
+}
+
+/// Drain the events of the running session into `ConnectionStatus`
+pub struct SessionEventsPlugin;
+
+impl Plugin for SessionEventsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ConnectionStatus>().add_system(poll_session_events);
+    }
+}
+
+fn poll_session_events(
+    p2p_session: Option<ResMut<P2PSession<GgrsConfig>>>,
+    spectator_session: Option<ResMut<SpectatorSession<GgrsConfig>>>,
+    mut status: ResMut<ConnectionStatus>,
+) {
+    let events: Vec<GGRSEvent<GgrsConfig>> = match (p2p_session, spectator_session) {
+        (Some(mut session), _) => session.events().collect(),
+        (_, Some(mut session)) => session.events().collect(),
+        _ => return,
+    };
+    for event in events {
+        match event {
+            GGRSEvent::NetworkInterrupted { addr, disconnect_timeout } => {
+                warn!("connection to {} interrupted, disconnecting in {}ms", addr, disconnect_timeout);
+                status.interrupted.insert(addr);
+            }
+            GGRSEvent::NetworkResumed { addr } => {
+                info!("connection to {} resumed", addr);
+                status.interrupted.remove(&addr);
+            }
+            GGRSEvent::Disconnected { addr } => {
+                warn!("{} disconnected", addr);
+                status.interrupted.remove(&addr);
+                status.disconnected.insert(addr);
+            }
+            GGRSEvent::WaitRecommendation { skip_frames } => {
+                info!("running ahead of the other peers, GGRS recommends waiting {} frames", skip_frames);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Exchange checksums of confirmed frames with the other peers and report the
+/// first frame where they disagree
+pub struct DesyncPlugin;
+
+impl Plugin for DesyncPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(send_checksums).add_system(compare_checksums);
+    }
+}
+
+fn send_checksums(
+    session: Option<Res<P2PSession<GgrsConfig>>>,
+    channel: Option<Res<PeerChannel>>,
+    history: Res<ChecksumHistory>,
+    settings: Res<MatchSettings>,
+    mut last_sent: Local<i32>,
+) {
+    let (session, channel) = match (session, channel) {
+        (Some(session), Some(channel)) if settings.desync_interval > 0 => (session, channel),
+        _ => {
+            *last_sent = 0;
+            return;
+        }
+    };
+    let confirmed = session.confirmed_frame();
+    if confirmed <= *last_sent {
+        return;
+    }
+    for (frame, checksum) in history.0.range(*last_sent + 1..=confirmed) {
+        if frame % settings.desync_interval as i32 == 0 {
+            channel.broadcast(&PeerMessage::Checksum { frame: *frame, checksum: *checksum });
+            *last_sent = *frame;
+        }
+    }
+}
+
+fn compare_checksums(
+    mut commands: Commands,
+    session: Option<Res<P2PSession<GgrsConfig>>>,
+    channel: Option<Res<PeerChannel>>,
+    history: Res<ChecksumHistory>,
+    report: Option<Res<DesyncReport>>,
+    mut pending: Local<Vec<(String, i32, Checksum)>>,
+) {
+    let (session, channel) = match (session, channel) {
+        (Some(session), Some(channel)) => (session, channel),
+        _ => {
+            pending.clear();
+            return;
+        }
+    };
+    for (peer, message) in channel.receive() {
+        match message {
+            PeerMessage::Checksum { frame, checksum } => pending.push((peer, frame, checksum)),
+            PeerMessage::Hello { .. } => {}
+        }
+    }
+
+    // our own checksum of a frame is only final once we confirmed it too
+    let confirmed = session.confirmed_frame();
+    let mut desync = None;
+    pending.retain(|(peer, frame, remote)| {
+        if *frame > confirmed {
+            return true;
+        }
+        match history.0.get(frame) {
+            Some(local) if local != remote && desync.is_none() => {
+                desync = Some(DesyncReport { frame: *frame, peer: Some(peer.clone()), components: local.differing(remote) });
+            }
+            Some(_) => {}
+            None => warn!("no checksum of frame {} left to compare with {}", frame, peer),
+        }
+        false
+    });
+
+    if let (Some(desync), None) = (desync, report) {
+        error!("desync with {:?} at frame {}, differing: {:?}", desync.peer, desync.frame, desync.components);
+        commands.insert_resource(desync);
+    }
+}
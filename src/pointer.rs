@@ -0,0 +1,79 @@
+use bevy::prelude::*;
+use bevy_hex::{
+    components::{Direction, Head, Player},
+    input::PointerSteering,
+    settings::{SessionMode, SessionSettings},
+    GgrsConfig,
+};
+use ggrs::P2PSession;
+
+/// Logical pixels a finger or the mouse has to travel to count as a swipe
+const SWIPE_DISTANCE: f32 = 40.;
+
+/// A finger or mouse button that is down
+pub struct Gesture {
+    /// where it went down, or where the last swipe ended
+    origin: Vec2,
+    swiped: bool,
+}
+
+/// Swipes steer in the direction of the swipe, a finger can keep swiping to
+/// turn again without lifting. Taps steer from the head of the local snake
+/// towards the tapped spot. The left mouse button works like a finger.
+#[allow(clippy::too_many_arguments)]
+pub fn pointer_steering(
+    touches: Res<Touches>,
+    mouse: Res<Input<MouseButton>>,
+    windows: Res<Windows>,
+    session: Option<Res<P2PSession<GgrsConfig>>>,
+    session_settings: Res<SessionSettings>,
+    cameras: Query<(&Camera, &GlobalTransform)>,
+    heads: Query<(&Player, &GlobalTransform), With<Head>>,
+    mut steering: ResMut<PointerSteering>,
+    mut gesture: Local<Option<Gesture>>,
+) {
+    let window = match windows.get_primary() {
+        Some(window) => window,
+        None => return,
+    };
+    // touches count from the top of the window, the cursor from the bottom
+    let touch = touches.iter().next().map(|touch| Vec2::new(touch.position().x, window.height() - touch.position().y));
+    let position = touch.or_else(|| mouse.pressed(MouseButton::Left).then(|| window.cursor_position()).flatten());
+
+    let position = match position {
+        Some(position) => position,
+        None => {
+            if let Some(Gesture { origin, swiped: false }) = gesture.take() {
+                let handle = match session_settings.mode {
+                    SessionMode::Online => session.and_then(|session| session.local_player_handles().first().copied()),
+                    _ => Some(0),
+                };
+                let head = heads.iter().find(|(player, _)| Some(player.handle) == handle);
+                if let (Some((_, head)), Ok((camera, camera_transform))) = (head, cameras.get_single()) {
+                    steering.pending = camera
+                        .world_to_viewport(camera_transform, head.translation())
+                        .map(|head| origin - head)
+                        .filter(|tap| *tap != Vec2::ZERO)
+                        .map(|tap| Direction::from_angle(tap.y.atan2(tap.x)));
+                }
+            }
+            return;
+        }
+    };
+
+    match gesture.as_mut() {
+        None => *gesture = Some(Gesture { origin: position, swiped: false }),
+        Some(gesture) => {
+            let swipe = position - gesture.origin;
+            if swipe.length() >= SWIPE_DISTANCE {
+                steering.pending = Some(Direction::from_angle(swipe.y.atan2(swipe.x)));
+                *gesture = Gesture { origin: position, swiped: true };
+            }
+        }
+    }
+}
+
+/// Forget taps and swipes made before the round started
+pub fn clear_pointer_steering(mut steering: ResMut<PointerSteering>) {
+    steering.pending = None;
+}
@@ -0,0 +1,122 @@
+use bevy::prelude::*;
+use bevy_hex::{
+    bindings::{is_bindable, KeyBindings, KeyLayout},
+    components::Direction,
+    settings::{MatchSettings, SessionSettings},
+};
+
+use crate::GameState;
+
+/// Bindings being edited on the rebind screen, applied when leaving it with Enter
+pub struct RebindDraft {
+    pub bindings: KeyBindings,
+    /// keyboard player being edited
+    pub player: usize,
+    /// keys pressed so far while recording a hex layout, in `Direction::ALL` order
+    pub recording: Option<Vec<KeyCode>>,
+}
+
+impl RebindDraft {
+    /// Direction the next recorded key steers in
+    pub fn next_direction(&self) -> Option<Direction> {
+        self.recording.as_ref().and_then(|keys| Direction::ALL.get(keys.len()).copied())
+    }
+}
+
+pub struct RebindPlugin;
+
+impl Plugin for RebindPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_system_set(SystemSet::on_enter(GameState::Rebind).with_system(start_rebind))
+            .add_system_set(SystemSet::on_update(GameState::Rebind).with_system(rebind_keys))
+            .add_system_set(SystemSet::on_exit(GameState::Rebind).with_system(end_rebind));
+    }
+}
+
+/// Load the bindings file, falling back to the default layouts
+pub fn load_bindings(session: &SessionSettings) -> KeyBindings {
+    #[cfg(not(target_arch = "wasm32"))]
+    match KeyBindings::load(&session.bindings_file) {
+        Ok(bindings) => return bindings,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => warn!("ignoring key bindings in {}: {}", session.bindings_file, err),
+    }
+    #[cfg(target_arch = "wasm32")]
+    let _ = session;
+    KeyBindings::default()
+}
+
+fn save_bindings(bindings: &KeyBindings, session: &SessionSettings) {
+    #[cfg(not(target_arch = "wasm32"))]
+    match bindings.save(&session.bindings_file) {
+        Ok(()) => info!("saved key bindings to {}", session.bindings_file),
+        Err(err) => error!("failed to save key bindings to {}: {}", session.bindings_file, err),
+    }
+    #[cfg(target_arch = "wasm32")]
+    let _ = (bindings, session);
+}
+
+fn start_rebind(mut commands: Commands, bindings: Res<KeyBindings>) {
+    commands.insert_resource(RebindDraft { bindings: bindings.clone(), player: 0, recording: None });
+}
+
+fn end_rebind(mut commands: Commands) {
+    commands.remove_resource::<RebindDraft>();
+}
+
+/// Tab picks the next player, P cycles through the built in profiles, H
+/// records one key per hex direction, N adds and X removes a keyboard
+/// player. Enter keeps the changes, Escape throws them away.
+fn rebind_keys(
+    mut commands: Commands,
+    mut keys: ResMut<Input<KeyCode>>,
+    mut draft: ResMut<RebindDraft>,
+    mut state: ResMut<State<GameState>>,
+    session: Res<SessionSettings>,
+) {
+    let player = draft.player;
+    if let Some(recorded) = &mut draft.recording {
+        if keys.just_pressed(KeyCode::Escape) {
+            keys.reset(KeyCode::Escape);
+            draft.recording = None;
+            return;
+        }
+        for key in keys.get_just_pressed() {
+            if is_bindable(*key) && !recorded.contains(key) {
+                recorded.push(*key);
+            }
+        }
+        if let Ok(layout) = <[KeyCode; 6]>::try_from(recorded.as_slice()) {
+            draft.bindings.players[player] = KeyLayout::Hex(layout);
+            draft.recording = None;
+        }
+        return;
+    }
+
+    if keys.just_pressed(KeyCode::Tab) {
+        draft.player = (player + 1) % draft.bindings.players.len();
+    } else if keys.just_pressed(KeyCode::P) {
+        let profiles = KeyLayout::PROFILES;
+        let current = profiles.iter().position(|(_, layout)| *layout == draft.bindings.players[player]);
+        let next = current.map_or(0, |i| (i + 1) % profiles.len());
+        draft.bindings.players[player] = profiles[next].1;
+    } else if keys.just_pressed(KeyCode::H) {
+        draft.recording = Some(Vec::new());
+    } else if keys.just_pressed(KeyCode::N) && draft.bindings.players.len() < MatchSettings::MAX_PLAYERS {
+        draft.bindings.players.push(KeyLayout::QWEASD);
+        draft.player = draft.bindings.players.len() - 1;
+    } else if keys.just_pressed(KeyCode::X) && draft.bindings.players.len() > 1 {
+        draft.bindings.players.remove(player);
+        draft.player = player.min(draft.bindings.players.len() - 1);
+    } else if keys.just_pressed(KeyCode::Return) {
+        keys.reset(KeyCode::Return);
+        save_bindings(&draft.bindings, &session);
+        commands.insert_resource(draft.bindings.clone());
+        state.set(GameState::MainMenu).unwrap();
+    } else if keys.just_pressed(KeyCode::Escape) {
+        // the main menu would quit on the same press
+        keys.reset(KeyCode::Escape);
+        state.set(GameState::MainMenu).unwrap();
+    }
+}
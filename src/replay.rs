@@ -0,0 +1,286 @@
+use std::fmt::Write as _;
+
+use bevy::{ecs::schedule::Stage, prelude::*};
+use bytemuck::Zeroable;
+use ggrs::InputStatus;
+
+use crate::{
+    checksum::FrameCount,
+    components::Pcg32RandomT,
+    input::SnakeInput,
+    rollback_schedule,
+    settings::MatchSettings,
+};
+
+/// Everything it takes to simulate a match again: the rng it started with,
+/// its rules and the input of every player for every frame
+#[derive(Clone, Default)]
+pub struct Replay {
+    pub rng: Pcg32RandomT,
+    pub settings: MatchSettings,
+    /// indexed by frame, then by player handle, `None` once a player disconnected
+    pub inputs: Vec<Vec<Option<SnakeInput>>>,
+}
+
+impl Replay {
+    const HEADER: &'static str = "hex-snake replay 2";
+    /// hex digits of one encoded input
+    const INPUT_WIDTH: usize = 2 * std::mem::size_of::<SnakeInput>();
+
+    pub fn new(rng: Pcg32RandomT, settings: MatchSettings) -> Self {
+        Self { rng, settings, inputs: Vec::new() }
+    }
+
+    /// Plain text: a header, one `key value` line per setting, then one line
+    /// of hex encoded `SnakeInput` bytes per frame, dashes for disconnected players
+    pub fn to_text(&self) -> String {
+        let (state, inc) = self.rng.raw();
+        let mut text = format!("{}\n", Self::HEADER);
+        let _ = writeln!(text, "rng {} {}", state, inc);
+        let _ = writeln!(text, "players {}", self.settings.num_players);
+        let _ = writeln!(text, "rounds {}", self.settings.rounds_to_win);
+        let _ = writeln!(text, "radius {}", self.settings.world_radius);
+        let _ = writeln!(text, "boundary {}", self.settings.boundary);
+        let _ = writeln!(text, "fps {}", self.settings.fps);
+        text.push_str("frames\n");
+        for inputs in &self.inputs {
+            for input in inputs {
+                match input {
+                    Some(input) => {
+                        for byte in bytemuck::bytes_of(input) {
+                            let _ = write!(text, "{:02x}", byte);
+                        }
+                    }
+                    None => text.push_str(&"-".repeat(Self::INPUT_WIDTH)),
+                }
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    pub fn from_text(text: &str) -> Result<Self, String> {
+        let mut lines = text.lines();
+        if lines.next() != Some(Self::HEADER) {
+            return Err("not a replay file".to_string());
+        }
+
+        let mut replay = Replay::default();
+        for line in lines.by_ref() {
+            let (key, value) = match line.split_once(' ') {
+                Some(pair) => pair,
+                None if line == "frames" => break,
+                None => return Err(format!("unexpected line {:?}", line)),
+            };
+            let invalid = |err: &dyn std::fmt::Debug| format!("invalid {} {:?}: {:?}", key, value, err);
+            match key {
+                "rng" => {
+                    let (state, inc) = value.split_once(' ').ok_or_else(|| invalid(&"expected two numbers"))?;
+                    replay.rng = Pcg32RandomT::from_raw(
+                        state.parse().map_err(|err| invalid(&err))?,
+                        inc.parse().map_err(|err| invalid(&err))?,
+                    );
+                }
+                "players" => replay.settings.num_players = value.parse().map_err(|err| invalid(&err))?,
+                "rounds" => replay.settings.rounds_to_win = value.parse().map_err(|err| invalid(&err))?,
+                "radius" => replay.settings.world_radius = value.parse().map_err(|err| invalid(&err))?,
+                "boundary" => replay.settings.boundary = value.parse().map_err(|err| invalid(&err))?,
+                "fps" => replay.settings.fps = value.parse().map_err(|err| invalid(&err))?,
+                _ => warn!("ignoring unknown replay setting {:?}", key),
+            }
+        }
+
+        let settings = &replay.settings;
+        if !(1..=MatchSettings::MAX_PLAYERS).contains(&settings.num_players) {
+            return Err(format!("players {} is not between 1 and {}", settings.num_players, MatchSettings::MAX_PLAYERS));
+        }
+        if !(MatchSettings::MIN_FPS..=MatchSettings::MAX_FPS).contains(&settings.fps) {
+            return Err(format!(
+                "fps {} is not between {} and {}",
+                settings.fps,
+                MatchSettings::MIN_FPS,
+                MatchSettings::MAX_FPS
+            ));
+        }
+        if settings.rounds_to_win == 0 {
+            return Err("rounds has to be at least 1".to_string());
+        }
+        if settings.world_radius < MatchSettings::min_radius(settings.num_players) {
+            return Err(format!("radius {} is too small for {} players", settings.world_radius, settings.num_players));
+        }
+
+        for (frame, line) in lines.enumerate() {
+            // one fixed width input per player, nothing more or less
+            if line.len() != Self::INPUT_WIDTH * replay.settings.num_players || !line.is_ascii() {
+                return Err(format!("frame {} has {:?} instead of {} inputs", frame, line, replay.settings.num_players));
+            }
+            let inputs = (0..line.len())
+                .step_by(Self::INPUT_WIDTH)
+                .map(|i| {
+                    let input = &line[i..i + Self::INPUT_WIDTH];
+                    if input.bytes().all(|c| c == b'-') {
+                        return Ok(None);
+                    }
+                    let bytes = (0..input.len())
+                        .step_by(2)
+                        .map(|j| u8::from_str_radix(&input[j..j + 2], 16))
+                        .collect::<Result<Vec<u8>, _>>()?;
+                    Ok(Some(*bytemuck::from_bytes::<SnakeInput>(&bytes)))
+                })
+                .collect::<Result<Vec<Option<SnakeInput>>, std::num::ParseIntError>>()
+                .map_err(|err| format!("frame {}: {}", frame, err))?;
+            replay.inputs.push(inputs);
+        }
+        Ok(replay)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        std::fs::write(path, self.to_text())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load(path: &str) -> std::io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Self::from_text(&text).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+    }
+}
+
+/// The match being recorded. Rolled back frames are recorded again, so the
+/// inputs of a frame are final once GGRS confirmed it.
+pub struct ReplayRecorder(pub Replay);
+
+pub(crate) fn record_inputs(
+    inputs: Res<Vec<(SnakeInput, InputStatus)>>,
+    frame: Res<FrameCount>,
+    recorder: Option<ResMut<ReplayRecorder>>,
+) {
+    if let Some(mut recorder) = recorder {
+        let recorded = &mut recorder.0.inputs;
+        recorded.truncate(frame.0 as usize);
+        recorded.push(
+            inputs
+                .iter()
+                .map(|(input, status)| (*status != InputStatus::Disconnected).then_some(*input))
+                .collect(),
+        );
+    }
+}
+
+/// Controls of the replay being played back
+pub struct ReplayPlayback {
+    pub replay: Replay,
+    pub paused: bool,
+    /// 1 plays at the speed the match was recorded with
+    pub speed: f64,
+    /// frames left to advance while paused
+    pub steps: u32,
+    accumulator: f64,
+}
+
+impl ReplayPlayback {
+    pub fn new(replay: Replay) -> Self {
+        Self { replay, paused: false, speed: 1.0, steps: 0, accumulator: 0.0 }
+    }
+
+    /// Whether every recorded frame has been played back
+    pub fn finished(&self, frame: &FrameCount) -> bool {
+        frame.0 as usize >= self.replay.inputs.len()
+    }
+
+    fn frames_due(&mut self, delta_seconds: f64) -> u32 {
+        if self.paused {
+            return std::mem::take(&mut self.steps);
+        }
+        self.accumulator += delta_seconds * self.speed * self.replay.settings.fps as f64;
+        let frames = self.accumulator.floor();
+        self.accumulator -= frames;
+        frames as u32
+    }
+}
+
+/// Runs the rollback schedule on the inputs of `ReplayPlayback` instead of a
+/// GGRS session. Does nothing while there is no replay to play.
+pub struct ReplayStage {
+    schedule: Schedule,
+}
+
+impl ReplayStage {
+    pub const LABEL: &'static str = "replay";
+}
+
+impl Default for ReplayStage {
+    fn default() -> Self {
+        Self { schedule: rollback_schedule() }
+    }
+}
+
+impl Stage for ReplayStage {
+    fn run(&mut self, world: &mut World) {
+        let delta_seconds = world.resource::<Time>().delta_seconds_f64();
+        let frames = match world.get_resource_mut::<ReplayPlayback>() {
+            Some(mut playback) => playback.frames_due(delta_seconds),
+            None => return,
+        };
+        for _ in 0..frames {
+            let frame = world.resource::<FrameCount>().0 as usize;
+            let inputs: Vec<(SnakeInput, InputStatus)> = match world.resource::<ReplayPlayback>().replay.inputs.get(frame) {
+                Some(inputs) => inputs
+                    .iter()
+                    .map(|input| match input {
+                        Some(input) => (*input, InputStatus::Confirmed),
+                        None => (SnakeInput::zeroed(), InputStatus::Disconnected),
+                    })
+                    .collect(),
+                None => break,
+            };
+            world.insert_resource(inputs);
+            self.schedule.run(world);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replay() -> Replay {
+        let mut replay = Replay::new(Pcg32RandomT::from_raw(7, 3), MatchSettings::default());
+        replay.inputs = vec![
+            vec![Some(SnakeInput::NONE), Some(SnakeInput::steer(crate::components::Direction::Left))],
+            vec![Some(SnakeInput::NONE.with_flags(SnakeInput::BOOST)), None],
+        ];
+        replay
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let text = replay().to_text();
+        let loaded = Replay::from_text(&text).unwrap();
+        assert_eq!(loaded.inputs, replay().inputs);
+        assert_eq!(loaded.rng.raw(), (7, 3));
+        assert_eq!(loaded.to_text(), text);
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let text = replay().to_text();
+        for (line, invalid) in [
+            ("players 2", "players 0"),
+            ("players 2", "players 200"),
+            ("fps 60", "fps 0"),
+            ("rounds 3", "rounds 0"),
+            ("radius 4", "radius 0"),
+        ] {
+            assert!(text.contains(line));
+            assert!(Replay::from_text(&text.replace(line, invalid)).is_err(), "{} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn rejects_frames_with_the_wrong_input_count() {
+        let mut text = replay().to_text();
+        text.push_str("010000\n");
+        assert!(Replay::from_text(&text).is_err());
+    }
+}
@@ -0,0 +1,378 @@
+use std::{collections::HashMap, fmt, hash::Hasher, str::FromStr};
+
+use bevy::log::warn;
+
+use crate::{hash::StableHasher, FPS};
+
+/// What happens to a head that steps off the map
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Boundary {
+    /// the edge of the map is a wall that kills the snake
+    #[default]
+    Walls,
+    /// leaving one edge re-enters the map on the opposite edge
+    Wrap,
+}
+
+impl FromStr for Boundary {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "walls" => Ok(Boundary::Walls),
+            "wrap" => Ok(Boundary::Wrap),
+            _ => Err(format!("unknown boundary {:?}, expected walls or wrap", s)),
+        }
+    }
+}
+
+impl fmt::Display for Boundary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Boundary::Walls => write!(f, "walls"),
+            Boundary::Wrap => write!(f, "wrap"),
+        }
+    }
+}
+
+/// How the rollback schedule is driven
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SessionMode {
+    /// peer to peer over a matchbox socket
+    #[default]
+    Online,
+    /// all players local, every frame is rolled back and resimulated to
+    /// check that the simulation is deterministic
+    SyncTest,
+    /// hot-seat, all players share this machine and no socket is opened
+    Local,
+    /// plays back the inputs of a recorded match
+    Replay,
+}
+
+impl FromStr for SessionMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "online" => Ok(SessionMode::Online),
+            "synctest" => Ok(SessionMode::SyncTest),
+            // single player is hot-seat where everybody else is a bot
+            "local" | "solo" => Ok(SessionMode::Local),
+            "replay" => Ok(SessionMode::Replay),
+            _ => Err(format!("unknown mode {:?}, expected online, synctest, local, solo or replay", s)),
+        }
+    }
+}
+
+/// How well bots play
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Difficulty {
+    /// heads greedily towards the closest crumple
+    Easy,
+    /// follows the shortest free path to the closest crumple
+    #[default]
+    Normal,
+    /// like normal, but stays clear of other heads and of dead ends
+    Hard,
+}
+
+impl FromStr for Difficulty {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "easy" => Ok(Difficulty::Easy),
+            "normal" => Ok(Difficulty::Normal),
+            "hard" => Ok(Difficulty::Hard),
+            _ => Err(format!("unknown difficulty {:?}, expected easy, normal or hard", s)),
+        }
+    }
+}
+
+/// Key-value launch options. Later sources override earlier ones:
+/// `HEX_SNAKE_<KEY>` environment variables, then `--key value` or
+/// `--key=value` command line arguments, or on wasm the `?key=value` query
+/// string of the page.
+#[derive(Clone, Debug, Default)]
+pub struct LaunchOptions(HashMap<String, String>);
+
+impl LaunchOptions {
+    const ENV_PREFIX: &'static str = "HEX_SNAKE_";
+
+    pub fn load() -> Self {
+        let mut options = Self::default();
+        options.add_env(std::env::vars());
+        options.add_args(std::env::args().skip(1));
+        #[cfg(target_arch = "wasm32")]
+        if let Some(search) = web_sys::window().and_then(|window| window.location().search().ok()) {
+            options.add_query(&search);
+        }
+        options
+    }
+
+    /// Parse the value of `key`, a value that does not parse is reported and ignored
+    pub fn get<T: FromStr>(&self, key: &str) -> Option<T>
+    where
+        T::Err: std::fmt::Debug,
+    {
+        let value = self.0.get(key)?;
+        match value.parse() {
+            Ok(value) => Some(value),
+            Err(err) => {
+                warn!("ignoring option {}={:?}: {:?}", key, value, err);
+                None
+            }
+        }
+    }
+
+    fn add_env(&mut self, vars: impl Iterator<Item = (String, String)>) {
+        for (name, value) in vars {
+            if let Some(key) = name.strip_prefix(Self::ENV_PREFIX) {
+                self.0.insert(key.to_lowercase().replace('_', "-"), value);
+            }
+        }
+    }
+
+    fn add_args(&mut self, args: impl Iterator<Item = String>) {
+        let mut args = args.peekable();
+        while let Some(arg) = args.next() {
+            let key = match arg.strip_prefix("--") {
+                Some(key) => key,
+                None => {
+                    warn!("ignoring argument {:?}", arg);
+                    continue;
+                }
+            };
+            if let Some((key, value)) = key.split_once('=') {
+                self.0.insert(key.to_string(), value.to_string());
+            } else if args.peek().map_or(false, |next| !next.starts_with("--")) {
+                self.0.insert(key.to_string(), args.next().unwrap());
+            } else {
+                // a bare flag
+                self.0.insert(key.to_string(), "true".to_string());
+            }
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn add_query(&mut self, query: &str) {
+        for pair in query.trim_start_matches('?').split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, "true"));
+            self.0.insert(percent_decode(key), percent_decode(value));
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let byte = std::str::from_utf8(&bytes[i + 1..i + 3])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match byte {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Rules of a match. Peers only play together when their rules agree, see
+/// `rules_hash`; the rollback tuning may differ between them.
+#[derive(Clone, Debug)]
+pub struct MatchSettings {
+    pub num_players: usize,
+    /// rounds a player has to win to win the match
+    pub rounds_to_win: u32,
+    pub world_radius: u32,
+    pub boundary: Boundary,
+    /// rollback frames per second, durations in frames are scaled to keep their length in seconds
+    pub fps: usize,
+    /// frames local input is held back before it is simulated, trades latency for fewer rollbacks
+    pub input_delay: usize,
+    /// frames a peer may simulate ahead of the last confirmed frame before it waits
+    pub max_prediction: usize,
+    /// confirmed frames between two checksum comparisons with the other peers, 0 turns them off
+    pub desync_interval: u32,
+}
+
+impl MatchSettings {
+    pub const MAX_PLAYERS: usize = 8;
+    pub const MIN_FPS: usize = 10;
+    pub const MAX_FPS: usize = 240;
+    /// GGRS keeps 128 frames of input per player, delayed input has to fit
+    pub const MAX_INPUT_DELAY: usize = 60;
+
+    /// Hash of everything the simulation depends on, the same on every
+    /// platform. Peers compare it in the lobby.
+    pub fn rules_hash(&self) -> u64 {
+        let mut hasher = StableHasher::default();
+        hasher.write(&(self.num_players as u64).to_le_bytes());
+        hasher.write(&self.rounds_to_win.to_le_bytes());
+        hasher.write(&self.world_radius.to_le_bytes());
+        hasher.write(&[self.boundary as u8]);
+        hasher.write(&(self.fps as u64).to_le_bytes());
+        hasher.finish()
+    }
+
+    /// Convert a duration given in frames at `FPS` to frames at `self.fps`
+    pub fn scale_frames(&self, frames: u32) -> u32 {
+        (frames as usize * self.fps / FPS).max(1) as u32
+    }
+
+    /// Smallest map that leaves a free hex between the start positions of
+    /// `num_players` snakes, see `spawn_layout`
+    pub fn min_radius(num_players: usize) -> u32 {
+        ((num_players as u32 + 2) / 3).max(1)
+    }
+
+    pub fn from_options(options: &LaunchOptions) -> Self {
+        let default = Self::default();
+        let num_players = options
+            .get("players")
+            .unwrap_or(default.num_players)
+            .clamp(1, Self::MAX_PLAYERS);
+        let radius = options.get("radius").unwrap_or(default.world_radius);
+        let min_radius = Self::min_radius(num_players);
+        if radius < min_radius {
+            warn!("radius {} is too small for {} players, using {}", radius, num_players, min_radius);
+        }
+        let input_delay = options.get("input-delay").unwrap_or(default.input_delay);
+        if input_delay > Self::MAX_INPUT_DELAY {
+            warn!("input delay {} is too long, using {}", input_delay, Self::MAX_INPUT_DELAY);
+        }
+        Self {
+            num_players,
+            rounds_to_win: options.get("rounds").unwrap_or(default.rounds_to_win).max(1),
+            world_radius: radius.max(min_radius),
+            boundary: options.get("boundary").unwrap_or(default.boundary),
+            fps: options.get("fps").unwrap_or(default.fps).clamp(Self::MIN_FPS, Self::MAX_FPS),
+            input_delay: input_delay.min(Self::MAX_INPUT_DELAY),
+            max_prediction: options.get("max-prediction").unwrap_or(default.max_prediction).max(1),
+            desync_interval: options.get("desync-interval").unwrap_or(default.desync_interval),
+        }
+    }
+}
+
+impl Default for MatchSettings {
+    fn default() -> Self {
+        Self {
+            num_players: 2,
+            rounds_to_win: 3,
+            world_radius: 4,
+            boundary: Boundary::Walls,
+            fps: FPS,
+            input_delay: 2,
+            max_prediction: 8,
+            desync_interval: FPS as u32,
+        }
+    }
+}
+
+/// Where the matchbox signaling server lives and which room to join
+#[derive(Clone, Debug)]
+pub struct ConnectionSettings {
+    pub signaling_url: String,
+    pub room: String,
+}
+
+impl ConnectionSettings {
+    pub fn from_options(options: &LaunchOptions, settings: &MatchSettings) -> Self {
+        Self {
+            signaling_url: options
+                .get("signaling")
+                .unwrap_or_else(|| "ws://127.0.0.1:3536".to_string()),
+            room: options
+                .get("room")
+                .unwrap_or_else(|| format!("next_{}", settings.num_players)),
+        }
+    }
+
+    pub fn room_url(&self) -> String {
+        format!("{}/{}", self.signaling_url.trim_end_matches('/'), self.room)
+    }
+}
+
+/// Which kind of GGRS session to start
+#[derive(Clone, Debug)]
+pub struct SessionSettings {
+    pub mode: SessionMode,
+    /// frames rolled back and resimulated every frame in sync test mode
+    pub check_distance: usize,
+    /// rng seed for sessions without peers to derive one from
+    pub seed: u64,
+    /// number of players steered by the computer, they take the highest handles
+    pub bots: usize,
+    pub difficulty: Difficulty,
+    /// where matches are recorded to and played back from
+    pub replay_file: String,
+    /// join online matches as a spectator instead of a player
+    pub spectate: bool,
+    /// keyboard layouts of the local players, see `KeyBindings`
+    pub bindings_file: String,
+    /// how far the left stick has to be pushed, from 0 to 1, before it steers
+    pub stick_deadzone: f32,
+}
+
+impl SessionSettings {
+    pub fn from_options(options: &LaunchOptions, settings: &MatchSettings) -> Self {
+        let mode = options.get("mode").unwrap_or_default();
+        let solo = options.get::<String>("mode").as_deref() == Some("solo");
+        let bots = match mode {
+            // bots only exist where their input is produced on this machine
+            SessionMode::Online | SessionMode::Replay => 0,
+            _ => options
+                .get("bots")
+                .unwrap_or(if solo { settings.num_players - 1 } else { 0 })
+                .min(settings.num_players),
+        };
+        // GGRS refuses sync tests that roll back as far as they may predict
+        let check_distance = options.get("check-distance").unwrap_or(7);
+        let max_check_distance = settings.max_prediction - 1;
+        if mode == SessionMode::SyncTest && check_distance > max_check_distance {
+            warn!(
+                "check distance {} does not fit the prediction window of {} frames, using {}",
+                check_distance, settings.max_prediction, max_check_distance
+            );
+        }
+        Self {
+            mode,
+            check_distance: check_distance.min(max_check_distance),
+            seed: options.get("seed").unwrap_or(1),
+            bots,
+            difficulty: options.get("difficulty").unwrap_or_default(),
+            replay_file: options
+                .get("replay-file")
+                .unwrap_or_else(|| "last_match.replay".to_string()),
+            spectate: mode == SessionMode::Online && options.get("spectate").unwrap_or(false),
+            bindings_file: options
+                .get("bindings-file")
+                .unwrap_or_else(|| "bindings.cfg".to_string()),
+            stick_deadzone: options.get("deadzone").unwrap_or(0.4f32).clamp(0., 1.),
+        }
+    }
+
+    pub fn is_bot(&self, handle: usize, settings: &MatchSettings) -> bool {
+        handle + self.bots >= settings.num_players
+    }
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        Self::from_options(&LaunchOptions::default(), &MatchSettings::default())
+    }
+}
@@ -0,0 +1,660 @@
+use std::collections::HashSet;
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy_ggrs::*;
+use ggrs::{InputStatus, P2PSession};
+
+use crate::{bindings::{GamepadAssignments, KeyBindings}, checksum::*, components::{*, Direction}, input::{self, *}, replay::*, settings::*, GgrsConfig, FPS, MOVEMENT_INTERVAL};
+
+/// Frames at `FPS` between spawning the snakes and letting them move
+pub const COUNTDOWN_FRAMES: u32 = 3 * FPS as u32;
+
+/// Frames at `FPS` the result of a round stays up before the next one is set up
+pub const ROUND_OVER_FRAMES: u32 = 3 * FPS as u32;
+
+#[derive(SystemLabel, Debug, Clone, Hash, Eq, PartialEq)]
+pub enum Systems {
+    AdvancePhase,
+    ActionSystem,
+    HeadMovement,
+    HeadCrumpleCollision,
+    SnakeCollision,
+    RoundResult,
+    SpawnSegment,
+    SpawnCrumple,
+    UpdateFollowers,
+}
+
+#[derive(Default, Reflect, Component)]
+pub struct SpawnCrumple();
+
+pub struct UpdateBody(Entity, Entity);
+
+pub struct SpawnSegment(Entity, Entity);
+
+/// Sent once for every head that dies, in ascending player handle order
+pub struct SnakeDeath {
+    pub entity: Entity,
+    pub handle: usize,
+    pub cause: DeathCause,
+}
+
+/// Outcome of the current round, part of the rollback state
+#[derive(Reflect, Default, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[reflect(Resource)]
+pub enum RoundResult {
+    #[default]
+    InProgress,
+    /// the last snake alive
+    Winner { handle: usize },
+    /// every snake died, also how a round without opponents ends
+    Draw,
+}
+
+/// Where the match stands. Part of the rollback state, so every peer moves
+/// from one phase to the next on the same frame.
+#[derive(Reflect, Default, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[reflect(Resource)]
+pub enum MatchPhase {
+    /// snakes and the first crumple of the next round still have to be spawned
+    #[default]
+    Setup,
+    Countdown { frames_left: u32 },
+    Playing,
+    RoundOver { frames_left: u32 },
+    MatchOver,
+}
+
+/// Rounds won by every player, part of the rollback state
+#[derive(Reflect, Default, Clone, Debug, Hash)]
+#[reflect(Resource)]
+pub struct MatchScore {
+    pub round: u32,
+    pub wins: Vec<u32>,
+}
+
+/// Everything the ui and persistence layers may want to know about a match
+#[derive(Clone, Copy, Debug)]
+pub enum MatchEvent {
+    RoundStarted { round: u32 },
+    CrumpleEaten { handle: usize, score: u32, length: u32 },
+    SnakeDied { handle: usize, cause: DeathCause },
+    RoundOver(RoundResult),
+    /// `winner` is `None` when a single player match ends
+    MatchOver { winner: Option<usize> },
+}
+
+/// Match events of simulated frames that may still be rolled back, by frame.
+/// They go out as `MatchEvent`s once their frame is confirmed, so resimulated
+/// and mispredicted frames do not report anything twice or wrongly.
+#[derive(Default)]
+pub struct PendingMatchEvents(pub Vec<(i32, MatchEvent)>);
+
+/// Sends match events from inside the rollback schedule
+#[derive(SystemParam)]
+pub struct MatchEvents<'w, 's> {
+    pending: ResMut<'w, PendingMatchEvents>,
+    frame: Res<'w, FrameCount>,
+    #[system_param(ignore)]
+    marker: std::marker::PhantomData<&'s ()>,
+}
+
+impl<'w, 's> MatchEvents<'w, 's> {
+    pub fn send(&mut self, event: MatchEvent) {
+        let frame = self.frame.0;
+        self.pending.0.push((frame, event));
+    }
+}
+
+#[derive(Default)]
+pub struct WorldSize {
+    pub radius: u32,
+    pub boundary: Boundary,
+}
+
+impl WorldSize {
+    pub fn contains(&self, hex: Hex) -> bool {
+        hex.length() <= self.radius as i32
+    }
+
+    /// Map a hex that left the board back onto it when wrapping is enabled.
+    /// The hexagonal map tiles the plane with copies centered on the six
+    /// rotations of (2N+1, -N), subtracting the right one lands on the board.
+    pub fn wrap(&self, hex: Hex) -> Hex {
+        if self.boundary == Boundary::Walls || self.contains(hex) {
+            return hex;
+        }
+        let n = self.radius as i32;
+        let mirror = Hex::new(2 * n + 1, -n);
+        (0..6)
+            .map(|steps| hex - mirror.rotate_around(Hex::ZERO, steps))
+            .find(|wrapped| self.contains(*wrapped))
+            .unwrap_or(hex)
+    }
+}
+
+/// The whole game simulation: the GGRS rollback schedule, its rollback state
+/// and the events it emits. Needs no rendering, so it runs just as well on
+/// top of `MinimalPlugins`. Expects `MatchSettings` and `SessionSettings`
+/// resources, falls back to their defaults.
+pub struct SnakeSimPlugin;
+
+impl Plugin for SnakeSimPlugin {
+    fn build(&self, app: &mut App) {
+        let settings = app.world.get_resource_or_insert_with(MatchSettings::default).clone();
+        GGRSPlugin::<GgrsConfig>::new()
+            .with_update_frequency(settings.fps)
+            .with_input_system(input::input)
+            .with_rollback_schedule(rollback_schedule())
+            .register_rollback_type::<Player>()
+            .register_rollback_type::<Head>()
+            .register_rollback_type::<HexHistory>()
+            .register_rollback_type::<Tail>()
+            .register_rollback_type::<Hex>()
+            .register_rollback_type::<Pcg32RandomT>()
+            .register_rollback_type::<Crumple>()
+            .register_rollback_type::<Segment>()
+            .register_rollback_type::<MovementCooldown>()
+            .register_rollback_type::<Body>()
+            .register_rollback_type::<SpawnCrumple>()
+            .register_rollback_type::<Dead>()
+            .register_rollback_type::<Score>()
+            .register_rollback_type::<Length>()
+            .register_rollback_type::<RoundResult>()
+            .register_rollback_type::<MatchPhase>()
+            .register_rollback_type::<MatchScore>()
+            .register_rollback_type::<FrameCount>()
+            .build(app);
+
+        app
+            .init_resource::<SessionSettings>()
+            .init_resource::<KeyBindings>()
+            .init_resource::<GamepadAssignments>()
+            .init_resource::<PointerSteering>()
+            .insert_resource(WorldSize { radius: settings.world_radius, boundary: settings.boundary })
+            .init_resource::<Events<SpawnCrumple>>()
+            .add_event::<SpawnSegment>()
+            .add_event::<UpdateBody>()
+            .add_event::<SnakeDeath>()
+            .add_event::<MatchEvent>()
+            .init_resource::<PendingMatchEvents>()
+            .add_system_to_stage(CoreStage::PostUpdate, send_confirmed_match_events)
+            .init_resource::<RoundResult>()
+            .init_resource::<MatchPhase>()
+            .init_resource::<MatchScore>()
+            .init_resource::<FrameCount>()
+            .init_resource::<ChecksumHistory>()
+            .add_stage_before(CoreStage::Update, ReplayStage::LABEL, ReplayStage::default());
+    }
+}
+
+/// Systems advancing the game by one rollback frame
+pub fn rollback_schedule() -> Schedule {
+    Schedule::default()
+        .with_stage(
+            "phase",
+            SystemStage::single_threaded()
+            .with_system(record_inputs)
+            .with_system(discard_resimulated_events.before(Systems::AdvancePhase))
+            .with_system(advance_phase.label(Systems::AdvancePhase))
+        )
+        .with_stage(
+            "action",
+            SystemStage::single_threaded()
+            .with_system_set(
+                SystemSet::new()
+                .with_run_criteria(is_playing)
+                .with_system(action_system.label(Systems::ActionSystem))
+                .with_system(head_movement.label(Systems::HeadMovement).after(Systems::ActionSystem))
+                .with_system(head_crumple_collision.label(Systems::HeadCrumpleCollision).after(Systems::HeadMovement))
+                .with_system(spawn_segment.label(Systems::SpawnSegment).after(Systems::HeadCrumpleCollision))
+                .with_system(update_body.after(Systems::SpawnSegment))
+                .with_system(update_followers.label(Systems::UpdateFollowers).after(Systems::HeadMovement))
+                .with_system(snake_collision.label(Systems::SnakeCollision).after(Systems::UpdateFollowers))
+                .with_system(round_result.label(Systems::RoundResult).after(Systems::SnakeCollision))
+            )
+            // also places the first crumple of a round while counting down
+            .with_system(spawn_crumple.label(Systems::SpawnCrumple).after(Systems::HeadCrumpleCollision))
+        )
+        .with_stage(
+            "checksum",
+            SystemStage::single_threaded()
+            .with_system(record_checksum)
+        )
+        // .with_stage(
+        //     "ROLLBACK_STAGE",
+        //     SystemStage::single_threaded()
+        //     .with_system(head_movement)
+        //     .with_run_criteria(FixedTimestep::step(0.75))
+        // )
+}
+
+/// The frame about to be simulated, and every later one, is simulated anew,
+/// so whatever it reported before no longer counts
+fn discard_resimulated_events(mut pending: ResMut<PendingMatchEvents>, frame: Res<FrameCount>) {
+    pending.0.retain(|(event_frame, _)| *event_frame < frame.0);
+}
+
+/// Send the pending match events of every frame that can no longer be
+/// rolled back
+fn send_confirmed_match_events(
+    mut pending: ResMut<PendingMatchEvents>,
+    frame: Res<FrameCount>,
+    session_type: Option<Res<SessionType>>,
+    p2p_session: Option<Res<P2PSession<GgrsConfig>>>,
+    session: Res<SessionSettings>,
+    mut match_events: EventWriter<MatchEvent>,
+) {
+    let confirmed = match session_type.as_deref() {
+        Some(SessionType::P2PSession) => p2p_session.map_or(-1, |session| session.confirmed_frame()),
+        // sync tests resimulate the last `check_distance` frames every frame
+        Some(SessionType::SyncTestSession) => match session.mode {
+            SessionMode::SyncTest => frame.0 - 1 - session.check_distance as i32,
+            _ => frame.0 - 1,
+        },
+        // spectators and replays only ever simulate confirmed inputs
+        _ => i32::MAX,
+    };
+    let (confirmed, waiting): (Vec<_>, Vec<_>) =
+        pending.0.drain(..).partition(|(event_frame, _)| *event_frame <= confirmed);
+    pending.0 = waiting;
+    for (_, event) in confirmed {
+        match_events.send(event);
+    }
+}
+
+/// Start positions and directions for `num_players` snakes, spread evenly
+/// over a ring around the center and heading clockwise along it so the
+/// layout looks the same from every snake's point of view. Maps smaller
+/// than `MatchSettings::min_radius` cannot keep the snakes apart.
+pub fn spawn_layout(num_players: usize, world_radius: u32) -> Vec<(Hex, Direction)> {
+    // leave at least one free hex between neighbouring snakes
+    let min_radius = MatchSettings::min_radius(num_players);
+    let radius = (world_radius / 2).max(min_radius).clamp(1, world_radius.max(1));
+    let ring = Hex::ZERO.ring(radius);
+    (0..num_players)
+        .map(|i| {
+            let index = i * ring.len() / num_players;
+            (ring[index], Direction::ALL[index / radius as usize])
+        })
+        .collect()
+}
+
+fn spawn_snakes(
+    commands: &mut Commands,
+    rip: &mut RollbackIdProvider,
+    settings: &MatchSettings,
+) {
+    for (handle, (hex, direction)) in spawn_layout(settings.num_players, settings.world_radius).into_iter().enumerate() {
+        let head = commands.spawn()
+        .insert(Player { handle })
+        .insert(Rollback::new(rip.next_id()))
+        .insert(hex)
+        .insert(HexHistory(Vec::new()))
+        .insert(Head::new(direction))
+        .insert(Tail)
+        .insert(Score(0))
+        .insert(Length(1))
+        .insert(MovementCooldown::new(settings.scale_frames(MOVEMENT_INTERVAL)))
+        .id();
+
+        commands.entity(head).insert(Body(vec![head]));
+    }
+}
+
+fn action_system(
+    inputs: Res<Vec<(SnakeInput, InputStatus)>>,
+    mut query: Query<(&mut Head, &Player, &Body), Without<Dead>>
+) {
+    for (mut head, player, body) in query.iter_mut() {
+        let (input, _) = inputs[player.handle];
+        let has_neck = body.0.len() > 1;
+        head.boosting = input.holds(SnakeInput::BOOST);
+
+        // turns only count on the frame their key goes down, the held ones
+        // are part of the rollback state so every peer sees the same presses
+        let turns = input.flags() & SnakeInput::TURNS;
+        let pressed_turns = turns & !head.held_turns;
+        head.held_turns = turns;
+
+        let direction = input.direction();
+        if direction != Direction::None {
+            head.steer(direction, has_neck);
+        } else {
+            match pressed_turns {
+                SnakeInput::TURN_LEFT => head.turn(-1, has_neck),
+                SnakeInput::TURN_RIGHT => head.turn(1, has_neck),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn head_movement(
+    mut query: Query<(&mut Hex, &mut Head, &mut HexHistory, &mut MovementCooldown), Without<Dead>>,
+    world_size: Res<WorldSize>,
+) {
+    for (mut hex, mut head, mut hex_history, mut movement_cooldown) in query.iter_mut() {
+        if movement_cooldown.tick(head.boosting) {
+            hex_history.0.push(*hex);
+            *hex = world_size.wrap(hex.neighbor(head.direction));
+            head.stepped();
+        }
+    }
+}
+
+fn is_playing(phase: Res<MatchPhase>) -> ShouldRun {
+    match *phase {
+        MatchPhase::Playing => ShouldRun::Yes,
+        _ => ShouldRun::No,
+    }
+}
+
+/// Drive the round flow: set up a fresh board, count down, play until the
+/// round has a result, show it and then either start over or end the match
+/// once somebody won `rounds_to_win` rounds. The result stays up for
+/// `ROUND_OVER_FRAMES` or until every connected player is ready.
+#[allow(clippy::too_many_arguments)]
+fn advance_phase(
+    mut commands: Commands,
+    inputs: Res<Vec<(SnakeInput, InputStatus)>>,
+    mut rip: ResMut<RollbackIdProvider>,
+    settings: Res<MatchSettings>,
+    mut phase: ResMut<MatchPhase>,
+    mut round_result: ResMut<RoundResult>,
+    mut match_score: ResMut<MatchScore>,
+    board_query: Query<Entity, Or<(With<Head>, With<Segment>, With<Crumple>)>>,
+    mut spawn_crumple: EventWriter<SpawnCrumple>,
+    mut match_events: MatchEvents,
+) {
+    *phase = match *phase {
+        MatchPhase::Setup => {
+            for entity in board_query.iter() {
+                commands.entity(entity).despawn();
+            }
+            spawn_snakes(&mut commands, &mut rip, &settings);
+            spawn_crumple.send(SpawnCrumple());
+            *round_result = RoundResult::InProgress;
+            match_score.wins.resize(settings.num_players, 0);
+            match_score.round += 1;
+            info!("round {} starting", match_score.round);
+            match_events.send(MatchEvent::RoundStarted { round: match_score.round });
+            MatchPhase::Countdown { frames_left: settings.scale_frames(COUNTDOWN_FRAMES) }
+        }
+        MatchPhase::Countdown { frames_left } if frames_left > 1 => MatchPhase::Countdown { frames_left: frames_left - 1 },
+        MatchPhase::Countdown { .. } => MatchPhase::Playing,
+        MatchPhase::Playing => match *round_result {
+            RoundResult::InProgress => MatchPhase::Playing,
+            result => {
+                if let RoundResult::Winner { handle } = result {
+                    match_score.wins[handle] += 1;
+                }
+                MatchPhase::RoundOver { frames_left: settings.scale_frames(ROUND_OVER_FRAMES) }
+            }
+        },
+        MatchPhase::RoundOver { frames_left } if frames_left > 1 && !all_ready(&inputs) => {
+            MatchPhase::RoundOver { frames_left: frames_left - 1 }
+        }
+        MatchPhase::RoundOver { .. } => {
+            let winner = match_score.wins.iter().position(|wins| *wins >= settings.rounds_to_win);
+            if winner.is_some() || settings.num_players == 1 {
+                info!("match over, winner: {:?}", winner);
+                match_events.send(MatchEvent::MatchOver { winner });
+                MatchPhase::MatchOver
+            } else {
+                MatchPhase::Setup
+            }
+        }
+        MatchPhase::MatchOver => MatchPhase::MatchOver,
+    };
+}
+
+fn all_ready(inputs: &[(SnakeInput, InputStatus)]) -> bool {
+    inputs
+        .iter()
+        .filter(|(_, status)| *status != InputStatus::Disconnected)
+        .all(|(input, _)| input.holds(SnakeInput::READY))
+}
+
+/// Place each requested crumple on a hex picked uniformly from the free cells
+/// of the map. When every cell is taken by a snake or a crumple the request
+/// is dropped, the next eaten crumple asks for a new one.
+fn spawn_crumple(
+    mut commands: Commands,
+    mut rip: ResMut<RollbackIdProvider>,
+    world_size: Res<WorldSize>,
+    mut spawn_crumple: EventReader<SpawnCrumple>,
+    mut rng: ResMut<Pcg32RandomT>,
+    occupied_query: Query<&Hex, Or<(With<Head>, With<Segment>, With<Crumple>)>>,
+) {
+    let mut occupied: HashSet<Hex> = occupied_query.iter().copied().collect();
+    for _ in spawn_crumple.iter() {
+        // the spiral order is the same on every peer, so the same index picks the same hex
+        let free: Vec<Hex> = Hex::ZERO
+            .spiral(world_size.radius)
+            .into_iter()
+            .filter(|hex| !occupied.contains(hex))
+            .collect();
+        if free.is_empty() {
+            info!("board is full, not spawning crumple");
+            continue;
+        }
+        let hex = free[rng.bounded_rand(free.len() as u32) as usize];
+        occupied.insert(hex);
+        info!("spawning crumple at q: {}, r: {}", hex.q, hex.r);
+        commands.spawn()
+        .insert(hex)
+        .insert(Crumple)
+        .insert(Rollback::new(rip.next_id()));
+    }
+}
+
+fn update_body(
+    mut query: Query<&mut Body>,
+    mut update_body: EventReader<UpdateBody>
+) {
+    for ev in update_body.iter() {
+        let mut body = query.get_mut(ev.0).unwrap();
+        body.0.push(ev.1);
+    }
+}
+
+fn spawn_segment(
+    mut commands: Commands,
+    mut rip: ResMut<RollbackIdProvider>,
+    mut query: Query<(Entity, &Hex)>,
+    mut spawn_segment: EventReader<SpawnSegment>,
+    mut update_body: EventWriter<UpdateBody>
+) {
+    for ev in spawn_segment.iter() {
+        info!("Spawning Segment");
+        let (entity, hex) = query.get_mut(ev.1).unwrap(); 
+        commands.entity(entity).remove::<Tail>();
+        let follower = commands.spawn()
+        .insert(*hex)
+        .insert(Rollback::new(rip.next_id()))
+        .insert(HexHistory(Vec::new()))
+        .insert(Tail)
+        .insert(Segment)
+        .id();
+        update_body.send(UpdateBody(ev.0, follower));
+    }
+}
+
+/// Move the body of every head that stepped this frame: each segment takes
+/// the hex the one before it left. Only reads rollback state, so it moves
+/// the same way after a rollback.
+fn update_followers(
+    head_query: Query<(&HexHistory, &Body, &MovementCooldown), (With<Head>, Without<Dead>)>,
+    mut segment_query: Query<(&mut Hex, &mut HexHistory), Without<Head>>,
+) {
+    for (head_history, body, cooldown) in head_query.iter() {
+        // the cooldown starts over on the frame the head steps
+        if cooldown.elapsed != 0 {
+            continue;
+        }
+        let mut vacated = match head_history.0.last() {
+            Some(hex) => *hex,
+            None => continue,
+        };
+        for segment in body.0.iter().skip(1) {
+            // segments spawned this frame only exist once the stage applies its commands
+            if let Ok((mut hex, mut hex_history)) = segment_query.get_mut(*segment) {
+                hex_history.0.push(*hex);
+                vacated = std::mem::replace(&mut *hex, vacated);
+            }
+        }
+    }
+}
+
+fn head_crumple_collision(
+    mut commands: Commands,
+    mut head_query: Query<(Entity, &Player, &Hex, &Body, &mut Score, &mut Length), (With<Head>, Without<Dead>)>,
+    crumple_query: Query<(Entity, &Hex), With<Crumple>>,
+    mut spawn_crumple: EventWriter<SpawnCrumple>,
+    mut spawn_segment: EventWriter<SpawnSegment>,
+    mut match_events: MatchEvents,
+) {
+    for (entity_head, player, hex_head, body, mut score, mut length) in head_query.iter_mut() {
+        for (crumple_entity, hex_crumple) in crumple_query.iter() {
+            if hex_head == hex_crumple {
+                info!("head crumple collision detected");
+                commands.entity(crumple_entity).despawn();
+                spawn_segment.send(SpawnSegment(entity_head, *body.0.last().unwrap()));
+                spawn_crumple.send(SpawnCrumple());
+                score.0 += 1;
+                length.0 += 1;
+                match_events.send(MatchEvent::CrumpleEaten { handle: player.handle, score: score.0, length: length.0 });
+            }
+        }
+    }
+}
+
+/// Kill heads that left a walled map, ran into a body segment or into another
+/// head. When heads meet the longer snake survives, on equal length both die.
+/// Snakes of players whose peer disconnected die where they are.
+fn snake_collision(
+    mut commands: Commands,
+    world_size: Res<WorldSize>,
+    inputs: Res<Vec<(SnakeInput, InputStatus)>>,
+    head_query: Query<(Entity, &Player, &Hex, &HexHistory, &Body, Option<&Dead>), With<Head>>,
+    segment_query: Query<(Entity, &Hex, &HexHistory), (Or<(With<Segment>, With<Tail>)>, Without<Head>)>,
+    mut snake_death: EventWriter<SnakeDeath>,
+) {
+    let mut heads: Vec<_> = head_query.iter().collect();
+    heads.sort_by_key(|(_, player, ..)| player.handle);
+
+    let mut deaths = Vec::new();
+    for (entity, player, hex, hex_history, body, dead) in heads.iter() {
+        if dead.is_some() {
+            continue;
+        }
+
+        if !world_size.contains(**hex) {
+            deaths.push((*entity, player.handle, DeathCause::Wall));
+            continue;
+        }
+
+        // a segment that has not moved yet still sits on its leader's hex
+        let body_hit = segment_query
+            .iter()
+            .find(|(_, segment_hex, segment_history)| !segment_history.0.is_empty() && *segment_hex == *hex)
+            .and_then(|(segment, ..)| heads.iter().find(|(_, _, _, _, other_body, _)| other_body.0.contains(&segment)));
+        if let Some((_, owner, ..)) = body_hit {
+            deaths.push((*entity, player.handle, DeathCause::Body { handle: owner.handle }));
+            continue;
+        }
+
+        for (other, other_player, other_hex, other_history, other_body, other_dead) in heads.iter() {
+            if other == entity {
+                continue;
+            }
+            let same_hex = other_hex == hex;
+            let swapped = hex_history.0.last() == Some(*other_hex) && other_history.0.last() == Some(*hex);
+            if !same_hex && !swapped {
+                continue;
+            }
+            // a dead head is just another obstacle
+            if other_dead.is_some() || other_body.0.len() >= body.0.len() {
+                deaths.push((*entity, player.handle, DeathCause::HeadOn { handle: other_player.handle }));
+                break;
+            }
+        }
+    }
+
+    // a snake that died anyway does not forfeit as well
+    for (entity, player, .., dead) in heads.iter() {
+        let died = deaths.iter().any(|(_, handle, _)| *handle == player.handle);
+        if dead.is_none() && !died && inputs[player.handle].1 == InputStatus::Disconnected {
+            deaths.push((*entity, player.handle, DeathCause::Forfeit));
+        }
+    }
+
+    for (entity, handle, cause) in deaths {
+        info!("player {} died: {:?}", handle, cause);
+        commands.entity(entity).insert(Dead);
+        snake_death.send(SnakeDeath { entity, handle, cause });
+    }
+}
+
+/// Forward deaths to the match event stream and decide the round once at
+/// most one snake is left alive
+fn round_result(
+    head_query: Query<&Player, (With<Head>, Without<Dead>)>,
+    mut snake_death: EventReader<SnakeDeath>,
+    mut round_result: ResMut<RoundResult>,
+    mut match_events: MatchEvents,
+) {
+    // `Dead` is only inserted once the stage applies its commands
+    let died: Vec<usize> = snake_death
+        .iter()
+        .map(|death| {
+            match_events.send(MatchEvent::SnakeDied { handle: death.handle, cause: death.cause });
+            death.handle
+        })
+        .collect();
+
+    if *round_result != RoundResult::InProgress || died.is_empty() {
+        return;
+    }
+
+    let total = head_query.iter().count();
+    let alive: Vec<usize> = head_query
+        .iter()
+        .map(|player| player.handle)
+        .filter(|handle| !died.contains(handle))
+        .collect();
+
+    let result = match alive.as_slice() {
+        [] => RoundResult::Draw,
+        [handle] if total > 1 => RoundResult::Winner { handle: *handle },
+        _ => return,
+    };
+    info!("round over: {:?}", result);
+    *round_result = result;
+    match_events.send(MatchEvent::RoundOver(result));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_lands_on_the_opposite_edge() {
+        let radius = 3;
+        let wrap = WorldSize { radius, boundary: Boundary::Wrap };
+        let walls = WorldSize { radius, boundary: Boundary::Walls };
+        for edge in Hex::ZERO.ring(radius) {
+            for direction in Direction::ALL {
+                let next = edge.neighbor(direction);
+                let wrapped = wrap.wrap(next);
+                assert!(wrap.contains(wrapped));
+                assert_eq!(walls.wrap(next), next);
+                if !wrap.contains(next) {
+                    // stepping back out of the wrapped hex re-enters where we left
+                    assert_eq!(wrap.wrap(wrapped.neighbor(direction.opposite())), edge);
+                }
+            }
+        }
+    }
+}
@@ -1,33 +1,464 @@
+use std::fmt::Write as _;
+
 use bevy::prelude::*;
-use iyes_loopless::prelude::*;
+use bevy_hex::{bindings::GamepadAssignments, checksum::{DesyncReport, FrameCount}, components::*, replay::ReplayPlayback, settings::MatchSettings, GgrsConfig, MatchPhase, MatchScore, RoundResult};
+use ggrs::{NetworkStats, P2PSession, SpectatorSession};
 
-use crate::GameState;
+use crate::{net::ConnectionStatus, rebind::RebindDraft, GameState, MenuNotice};
 
 pub struct UiPlugin;
 
 impl Plugin for UiPlugin {
     fn build(&self, app: &mut App) {
         app
-            .add_loopless_state(GameState::Start)
-            .add_enter_system(GameState::Start, start_menu);
-    }
-}
-
-fn start_menu(mut commands: Commands, asset_server: Res<AssetServer>) {
-    commands.spawn_bundle(UiCameraBundle::default());
-    commands.spawn_bundle(TextBundle {
-        text: Text::with_section(
-            "Use The Arrow Keys To Move",
-            TextStyle {
-                font: asset_server.load("fonts/BebasNeue-Regular.ttf"),
-                font_size: 100.0,
-                color: Color::WHITE,
-            },
-            TextAlignment {
-                horizontal: HorizontalAlign::Center,
-                ..Default::default()
-            },
-        ),
+            .add_startup_system(load_font)
+            .add_system(show_desync_report)
+            .add_system(show_replay_status)
+            .add_system(show_network_stats)
+            .add_system(show_interruptions)
+            .add_system_set(SystemSet::on_enter(GameState::MainMenu).with_system(main_menu))
+            .add_system_set(SystemSet::on_update(GameState::MainMenu).with_system(update_main_menu))
+            .add_system_set(SystemSet::on_exit(GameState::MainMenu).with_system(despawn_screen))
+            .add_system_set(SystemSet::on_enter(GameState::Rebind).with_system(rebind))
+            .add_system_set(SystemSet::on_update(GameState::Rebind).with_system(update_rebind))
+            .add_system_set(SystemSet::on_exit(GameState::Rebind).with_system(despawn_screen))
+            .add_system_set(SystemSet::on_enter(GameState::Lobby).with_system(lobby))
+            .add_system_set(SystemSet::on_exit(GameState::Lobby).with_system(despawn_screen))
+            .add_system_set(SystemSet::on_enter(GameState::Countdown).with_system(countdown))
+            .add_system_set(SystemSet::on_update(GameState::Countdown).with_system(update_countdown))
+            .add_system_set(SystemSet::on_exit(GameState::Countdown).with_system(despawn_screen))
+            .add_system_set(SystemSet::on_enter(GameState::Playing).with_system(scores))
+            .add_system_set(SystemSet::on_update(GameState::Playing).with_system(update_scores))
+            .add_system_set(SystemSet::on_exit(GameState::Playing).with_system(despawn_screen))
+            .add_system_set(SystemSet::on_enter(GameState::RoundOver).with_system(round_over))
+            .add_system_set(SystemSet::on_exit(GameState::RoundOver).with_system(despawn_screen))
+            .add_system_set(SystemSet::on_enter(GameState::MatchOver).with_system(match_over))
+            .add_system_set(SystemSet::on_exit(GameState::MatchOver).with_system(despawn_screen));
+    }
+}
+
+struct UiFont(Handle<Font>);
+
+/// Text belonging to the current state, removed when leaving it
+#[derive(Component)]
+struct ScreenText;
+
+#[derive(Component)]
+struct MenuText;
+
+#[derive(Component)]
+struct RebindText;
+
+#[derive(Component)]
+struct CountdownText;
+
+#[derive(Component)]
+struct ScoreText;
+
+/// Stays up across states until the match is left
+#[derive(Component)]
+struct DesyncText;
+
+#[derive(Component)]
+struct ReplayText;
+
+#[derive(Component)]
+struct NetworkText;
+
+#[derive(Component)]
+struct InterruptionText;
+
+fn load_font(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(UiFont(asset_server.load("fonts/BebasNeue-Regular.ttf")));
+}
+
+/// Text in the middle of the screen
+fn centered_text(font: &UiFont, value: impl Into<String>, font_size: f32) -> TextBundle {
+    TextBundle::from_section(
+        value,
+        TextStyle {
+            font: font.0.clone(),
+            font_size,
+            color: Color::WHITE,
+        },
+    )
+    .with_text_alignment(TextAlignment::CENTER)
+    .with_style(Style {
+        margin: UiRect::all(Val::Auto),
         ..default()
-    });
+    })
+}
+
+fn despawn_screen(mut commands: Commands, query: Query<Entity, With<ScreenText>>) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+const MENU: &str = "Hex Snake\n\nEnter to play\nB to rebind keys\nEsc to quit";
+
+fn main_menu(
+    mut commands: Commands,
+    font: Res<UiFont>,
+    assignments: Res<GamepadAssignments>,
+    notice: Option<Res<MenuNotice>>,
+) {
+    commands
+        .spawn_bundle(centered_text(&font, menu_text(&assignments, notice.as_deref()), 100.0))
+        .insert(ScreenText)
+        .insert(MenuText);
+}
+
+fn menu_text(assignments: &GamepadAssignments, notice: Option<&MenuNotice>) -> String {
+    let mut value = String::from(MENU);
+    if let Some(notice) = notice {
+        let _ = write!(value, "\n\n{}", notice.0);
+    }
+    for (handle, gamepad) in assignments.players.iter().enumerate() {
+        if let Some(gamepad) = gamepad {
+            let _ = write!(value, "\nGamepad {}: player {}", gamepad.id, handle + 1);
+        }
+    }
+    value
+}
+
+fn update_main_menu(
+    assignments: Res<GamepadAssignments>,
+    notice: Option<Res<MenuNotice>>,
+    mut query: Query<&mut Text, With<MenuText>>,
+) {
+    if !assignments.is_changed() && !notice.as_ref().map_or(false, |notice| notice.is_changed()) {
+        return;
+    }
+    for mut text in query.iter_mut() {
+        text.sections[0].value = menu_text(&assignments, notice.as_deref());
+    }
+}
+
+fn rebind(mut commands: Commands, font: Res<UiFont>) {
+    commands
+        .spawn_bundle(centered_text(&font, "", 50.0))
+        .insert(ScreenText)
+        .insert(RebindText);
+}
+
+fn update_rebind(draft: Option<Res<RebindDraft>>, mut query: Query<&mut Text, With<RebindText>>) {
+    let draft = match draft {
+        Some(draft) if draft.is_changed() => draft,
+        _ => return,
+    };
+    let mut value = String::from("Key bindings\n\n");
+    for (i, layout) in draft.bindings.players.iter().enumerate() {
+        let selected = if i == draft.player { "> " } else { "" };
+        let _ = writeln!(value, "{}Player {}: {}", selected, i + 1, layout);
+    }
+    match draft.next_direction() {
+        Some(direction) => {
+            let _ = write!(value, "\nPress the key for {:?}\nEsc cancels", direction);
+        }
+        None => value.push_str(
+            "\nTab next player, P profile, H record hex keys\nN add player, X remove player\nEnter save, Esc discard",
+        ),
+    }
+    for mut text in query.iter_mut() {
+        text.sections[0].value = value.clone();
+    }
+}
+
+fn lobby(mut commands: Commands, font: Res<UiFont>, settings: Res<MatchSettings>) {
+    commands.remove_resource::<MenuNotice>();
+    let text = format!("Waiting for {} players", settings.num_players);
+    commands
+        .spawn_bundle(centered_text(&font, text, 80.0))
+        .insert(ScreenText);
+}
+
+fn countdown(mut commands: Commands, font: Res<UiFont>) {
+    commands
+        .spawn_bundle(centered_text(&font, "", 200.0))
+        .insert(ScreenText)
+        .insert(CountdownText);
+}
+
+fn update_countdown(
+    phase: Res<MatchPhase>,
+    settings: Res<MatchSettings>,
+    mut query: Query<&mut Text, With<CountdownText>>,
+) {
+    let seconds = match *phase {
+        MatchPhase::Countdown { frames_left } => (frames_left as usize + settings.fps - 1) / settings.fps,
+        _ => return,
+    };
+    for mut text in query.iter_mut() {
+        text.sections[0].value = seconds.to_string();
+    }
+}
+
+fn scores(mut commands: Commands, font: Res<UiFont>) {
+    commands
+        .spawn_bundle(
+            TextBundle::from_section(
+                "",
+                TextStyle {
+                    font: font.0.clone(),
+                    font_size: 40.0,
+                    color: Color::WHITE,
+                },
+            )
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    left: Val::Px(10.0),
+                    top: Val::Px(10.0),
+                    ..default()
+                },
+                ..default()
+            }),
+        )
+        .insert(ScreenText)
+        .insert(ScoreText);
+}
+
+fn update_scores(
+    match_score: Res<MatchScore>,
+    player_query: Query<(&Player, &Score, Option<&Dead>), With<Head>>,
+    mut query: Query<&mut Text, With<ScoreText>>,
+) {
+    let mut players: Vec<_> = player_query.iter().collect();
+    players.sort_by_key(|(player, ..)| player.handle);
+    let mut value = format!("Round {}", match_score.round);
+    for (player, score, dead) in players {
+        let wins = match_score.wins.get(player.handle).copied().unwrap_or(0);
+        let status = if dead.is_some() { " (dead)" } else { "" };
+        value += &format!("\nPlayer {}: {} points, {} wins{}", player.handle + 1, score.0, wins, status);
+    }
+    for mut text in query.iter_mut() {
+        text.sections[0].value = value.clone();
+    }
+}
+
+fn round_over(mut commands: Commands, font: Res<UiFont>, round_result: Res<RoundResult>, match_score: Res<MatchScore>) {
+    let mut text = match *round_result {
+        RoundResult::Winner { handle } => format!("Player {} wins the round", handle + 1),
+        _ => "Draw".to_string(),
+    };
+    for (handle, wins) in match_score.wins.iter().enumerate() {
+        text += &format!("\nPlayer {}: {}", handle + 1, wins);
+    }
+    commands
+        .spawn_bundle(centered_text(&font, text, 80.0))
+        .insert(ScreenText);
+}
+
+fn match_over(mut commands: Commands, font: Res<UiFont>, settings: Res<MatchSettings>, match_score: Res<MatchScore>) {
+    let winner = match_score.wins.iter().position(|wins| *wins >= settings.rounds_to_win);
+    let text = match winner {
+        Some(handle) => format!("Player {} wins the match", handle + 1),
+        None => "Game over".to_string(),
+    };
+    commands
+        .spawn_bundle(centered_text(&font, text + "\n\nEnter for main menu", 100.0))
+        .insert(ScreenText);
+}
+
+fn show_desync_report(
+    mut commands: Commands,
+    font: Res<UiFont>,
+    report: Option<Res<DesyncReport>>,
+    query: Query<Entity, With<DesyncText>>,
+) {
+    let report = match report {
+        Some(report) if report.is_added() => report,
+        Some(_) => return,
+        None => {
+            for entity in query.iter() {
+                commands.entity(entity).despawn_recursive();
+            }
+            return;
+        }
+    };
+    let with = match &report.peer {
+        Some(peer) => format!(" with {}", peer),
+        None => String::new(),
+    };
+    let text = format!("Desync{} at frame {}\n{}", with, report.frame, report.components.join("\n"));
+    commands
+        .spawn_bundle(
+            TextBundle::from_section(
+                text,
+                TextStyle {
+                    font: font.0.clone(),
+                    font_size: 40.0,
+                    color: Color::RED,
+                },
+            )
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    right: Val::Px(10.0),
+                    top: Val::Px(10.0),
+                    ..default()
+                },
+                ..default()
+            }),
+        )
+        .insert(DesyncText);
+}
+
+fn show_replay_status(
+    mut commands: Commands,
+    font: Res<UiFont>,
+    playback: Option<Res<ReplayPlayback>>,
+    frame: Res<FrameCount>,
+    mut query: Query<(Entity, &mut Text), With<ReplayText>>,
+) {
+    let playback = match playback {
+        Some(playback) => playback,
+        None => {
+            for (entity, _) in query.iter() {
+                commands.entity(entity).despawn_recursive();
+            }
+            return;
+        }
+    };
+    let status = if playback.paused { "paused".to_string() } else { format!("x{}", playback.speed) };
+    let value = format!(
+        "Replay frame {}/{} {}\nSpace pause, . step, up/down speed",
+        frame.0,
+        playback.replay.inputs.len(),
+        status
+    );
+    if let Ok((_, mut text)) = query.get_single_mut() {
+        text.sections[0].value = value;
+        return;
+    }
+    commands
+        .spawn_bundle(
+            TextBundle::from_section(
+                value,
+                TextStyle {
+                    font: font.0.clone(),
+                    font_size: 40.0,
+                    color: Color::WHITE,
+                },
+            )
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    left: Val::Px(10.0),
+                    bottom: Val::Px(10.0),
+                    ..default()
+                },
+                ..default()
+            }),
+        )
+        .insert(ReplayText);
+}
+
+fn format_stats(text: &mut String, name: &str, stats: &NetworkStats) {
+    let _ = writeln!(
+        text,
+        "{}: {}ms ping, {} kbps, {} frames behind (remote {}), {} queued",
+        name,
+        stats.ping,
+        stats.kbps_sent,
+        stats.local_frames_behind,
+        stats.remote_frames_behind,
+        stats.send_queue_len,
+    );
+}
+
+/// F3 toggles the rollback settings and GGRS stats of every remote peer
+fn show_network_stats(
+    mut commands: Commands,
+    font: Res<UiFont>,
+    keys: Res<Input<KeyCode>>,
+    settings: Res<MatchSettings>,
+    p2p_session: Option<Res<P2PSession<GgrsConfig>>>,
+    spectator_session: Option<Res<SpectatorSession<GgrsConfig>>>,
+    mut visible: Local<bool>,
+    mut query: Query<(Entity, &mut Text), With<NetworkText>>,
+) {
+    if keys.just_pressed(KeyCode::F3) {
+        *visible = !*visible;
+    }
+    if !*visible {
+        for (entity, _) in query.iter() {
+            commands.entity(entity).despawn_recursive();
+        }
+        return;
+    }
+
+    let mut value = format!(
+        "{} fps, input delay {}, max prediction {}, desync interval {}\n",
+        settings.fps, settings.input_delay, settings.max_prediction, settings.desync_interval
+    );
+    if let Some(session) = &p2p_session {
+        for handle in session.remote_player_handles() {
+            match session.network_stats(handle) {
+                Ok(stats) => format_stats(&mut value, &format!("Player {}", handle + 1), &stats),
+                Err(err) => {
+                    let _ = writeln!(value, "Player {}: {}", handle + 1, err);
+                }
+            }
+        }
+    }
+    if let Some(session) = &spectator_session {
+        match session.network_stats() {
+            Ok(stats) => format_stats(&mut value, "Host", &stats),
+            Err(err) => {
+                let _ = writeln!(value, "Host: {}", err);
+            }
+        }
+        let _ = writeln!(value, "{} frames behind the host", session.frames_behind_host());
+    }
+
+    if let Ok((_, mut text)) = query.get_single_mut() {
+        text.sections[0].value = value;
+        return;
+    }
+    commands
+        .spawn_bundle(
+            TextBundle::from_section(
+                value,
+                TextStyle {
+                    font: font.0.clone(),
+                    font_size: 30.0,
+                    color: Color::YELLOW,
+                },
+            )
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    right: Val::Px(10.0),
+                    bottom: Val::Px(10.0),
+                    ..default()
+                },
+                ..default()
+            }),
+        )
+        .insert(NetworkText);
+}
+
+/// Tell why the game stopped while GGRS waits for peers it lost contact with
+fn show_interruptions(
+    mut commands: Commands,
+    font: Res<UiFont>,
+    status: Res<ConnectionStatus>,
+    query: Query<Entity, With<InterruptionText>>,
+) {
+    if !status.is_changed() {
+        return;
+    }
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    if status.interrupted.is_empty() {
+        return;
+    }
+    let mut peers: Vec<&str> = status.interrupted.iter().map(String::as_str).collect();
+    peers.sort_unstable();
+    commands
+        .spawn_bundle(centered_text(&font, format!("Waiting for\n{}", peers.join("\n")), 60.0))
+        .insert(InterruptionText);
 }
@@ -0,0 +1,125 @@
+//! Runs single frames of the rollback schedule on hand placed snakes and
+//! checks who `snake_collision` kills and why.
+
+use bevy::{ecs::schedule::Stage, prelude::*};
+use bevy_hex::{
+    components::{Body, DeathCause, Dead, Direction, Head, Hex, HexHistory, Length, MovementCooldown, Pcg32RandomT, Player, Score, Segment, Tail},
+    input::SnakeInput,
+    *,
+};
+use ggrs::InputStatus;
+
+/// Cooldowns that step on the next frame, or not for the rest of the test
+const STEPS: u32 = 1;
+const STILL: u32 = u32::MAX;
+
+fn playing_world() -> App {
+    let mut app = App::new();
+    app.insert_resource(Pcg32RandomT::new(1, 1))
+        .insert_resource(MatchPhase::Playing)
+        .add_plugins(MinimalPlugins)
+        .add_plugin(SnakeSimPlugin);
+    app
+}
+
+/// Spawn the snake of `handle` with its head on the first of `hexes` and a
+/// segment on each of the others. Every segment has moved before.
+fn spawn_snake(world: &mut World, handle: usize, direction: Direction, hexes: &[Hex], interval: u32) -> Entity {
+    let head = world
+        .spawn()
+        .insert_bundle((
+            Player { handle },
+            hexes[0],
+            HexHistory(Vec::new()),
+            Head::new(direction),
+            Score(0),
+            Length(hexes.len() as u32),
+            MovementCooldown::new(interval),
+        ))
+        .id();
+    let mut body = vec![head];
+    for hex in &hexes[1..] {
+        body.push(world.spawn().insert_bundle((*hex, HexHistory(vec![*hex]), Segment)).id());
+    }
+    world.entity_mut(*body.last().unwrap()).insert(Tail);
+    world.entity_mut(head).insert(Body(body));
+    head
+}
+
+/// Simulate one frame without input and return the deaths it reported
+fn deaths(app: &mut App) -> Vec<(usize, DeathCause)> {
+    app.world.insert_resource(vec![(SnakeInput::NONE, InputStatus::Confirmed); 2]);
+    rollback_schedule().run(&mut app.world);
+    app.world
+        .resource::<PendingMatchEvents>()
+        .0
+        .iter()
+        .filter_map(|(_, event)| match event {
+            MatchEvent::SnakeDied { handle, cause } => Some((*handle, *cause)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn running_into_a_body_kills() {
+    let middle = Hex::ZERO;
+    let mut app = playing_world();
+    spawn_snake(&mut app.world, 0, Direction::Right, &[middle.neighbor(Direction::Left)], STEPS);
+    spawn_snake(&mut app.world, 1, Direction::UpRight, &[middle.neighbor(Direction::UpRight), middle], STILL);
+    assert_eq!(deaths(&mut app), vec![(0, DeathCause::Body { handle: 1 })]);
+}
+
+#[test]
+fn segments_that_never_moved_are_skipped() {
+    let middle = Hex::ZERO;
+    let mut app = playing_world();
+    spawn_snake(&mut app.world, 0, Direction::Right, &[middle.neighbor(Direction::Left)], STEPS);
+    let head = spawn_snake(&mut app.world, 1, Direction::UpRight, &[middle.neighbor(Direction::UpRight), middle], STILL);
+    // a fresh segment still sits on its leader's hex until the snake steps
+    let segment = app.world.get::<Body>(head).unwrap().0[1];
+    app.world.get_mut::<HexHistory>(segment).unwrap().0.clear();
+    assert!(deaths(&mut app).is_empty());
+}
+
+#[test]
+fn heads_of_equal_length_both_die() {
+    let middle = Hex::ZERO;
+    let mut app = playing_world();
+    spawn_snake(&mut app.world, 0, Direction::Right, &[middle.neighbor(Direction::Left)], STEPS);
+    spawn_snake(&mut app.world, 1, Direction::Left, &[middle.neighbor(Direction::Right)], STEPS);
+    assert_eq!(
+        deaths(&mut app),
+        vec![(0, DeathCause::HeadOn { handle: 1 }), (1, DeathCause::HeadOn { handle: 0 })]
+    );
+}
+
+#[test]
+fn the_longer_head_survives() {
+    let left = Hex::ZERO.neighbor(Direction::Left);
+    let mut app = playing_world();
+    spawn_snake(&mut app.world, 0, Direction::Right, &[left, left.neighbor(Direction::Left)], STEPS);
+    spawn_snake(&mut app.world, 1, Direction::Left, &[Hex::ZERO.neighbor(Direction::Right)], STEPS);
+    assert_eq!(deaths(&mut app), vec![(1, DeathCause::HeadOn { handle: 0 })]);
+}
+
+#[test]
+fn heads_swapping_hexes_meet() {
+    let mut app = playing_world();
+    spawn_snake(&mut app.world, 0, Direction::Right, &[Hex::ZERO], STEPS);
+    spawn_snake(&mut app.world, 1, Direction::Left, &[Hex::ZERO.neighbor(Direction::Right)], STEPS);
+    assert_eq!(
+        deaths(&mut app),
+        vec![(0, DeathCause::HeadOn { handle: 1 }), (1, DeathCause::HeadOn { handle: 0 })]
+    );
+}
+
+#[test]
+fn a_dead_head_is_an_obstacle() {
+    let left = Hex::ZERO.neighbor(Direction::Left);
+    let mut app = playing_world();
+    spawn_snake(&mut app.world, 0, Direction::Right, &[left, left.neighbor(Direction::Left)], STEPS);
+    let dead = spawn_snake(&mut app.world, 1, Direction::Left, &[Hex::ZERO], STILL);
+    app.world.entity_mut(dead).insert(Dead);
+    assert_eq!(deaths(&mut app), vec![(0, DeathCause::HeadOn { handle: 1 })]);
+}